@@ -1,73 +1,911 @@
-use std::{path::Path, process::ExitCode};
+use std::{
+    collections::BTreeMap,
+    io::Write as _,
+    os::unix::process::ExitStatusExt as _,
+    path::{Path, PathBuf},
+    process::{ExitCode, ExitStatus},
+    time::{Duration, Instant},
+};
 
-use tracing::info;
+use tracing::{error, info, warn};
 
-use crate::{core, stats};
+use crate::{DatabaseGuard, core, stats};
 
-pub async fn serve(path: &Path) {
-    let config = core::Config::from_env();
-    let (server, cancellation_token) = core::start_server(path, config).await;
+#[cfg(feature = "postgres")]
+pub use crate::query::OutputFormat;
 
-    match tokio::signal::ctrl_c().await {
-        Ok(()) => {
-            info!("Received shutdown signal, shutting down...");
-            cancellation_token.cancel();
-            server.await.unwrap();
-            stats::log_usage();
+/// Exit code of `serve` when another server is already listening on the socket
+const EXIT_SOCKET_IN_USE: u8 = 3;
+
+pub async fn serve(path: &Path, wait: bool) -> ExitCode {
+    serve_with_config(path, core::Config::from_env(), wait).await
+}
+
+async fn serve_with_config(path: &Path, config: core::Config, wait: bool) -> ExitCode {
+    if core::server_running(path).await {
+        if !wait {
+            error!("Another server is already listening on {}", path.display());
+            return ExitCode::from(EXIT_SOCKET_IN_USE);
         }
-        Err(err) => {
-            info!("Unable to listen for shutdown signal: {}", err);
+        info!("Waiting for the server on {} to exit...", path.display());
+        while core::server_running(path).await {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         }
     }
+    let post_serve_command = config.post_serve_command().map(String::from);
+    if let Some(command) = config.pre_serve_command()
+        && let Err(e) = run_serve_command("PRE_SERVE_COMMAND", command).await
+    {
+        error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    let (server, cancellation_token) = core::start_server(path, config).await;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => match result {
+            Ok(()) => {
+                info!("Received shutdown signal, shutting down...");
+                cancellation_token.cancel();
+            }
+            Err(err) => {
+                info!("Unable to listen for shutdown signal: {}", err);
+                return ExitCode::SUCCESS;
+            }
+        },
+        // Cancelled by a shutdown request over the socket
+        _ = cancellation_token.cancelled() => {}
+    }
+    server.await.unwrap();
+    stats::log_usage();
+    if let Some(command) = post_serve_command
+        && let Err(e) = run_serve_command("POST_SERVE_COMMAND", &command).await
+    {
+        error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Run one of `serve`'s setup or teardown commands through `sh -c`
+async fn run_serve_command(setting: &str, command: &str) -> Result<(), String> {
+    info!("Running {}: {}", setting, command);
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .await
+        .map_err(|e| format!("Failed to run {setting}: {e}"))?;
+    if !status.success() {
+        return Err(format!("{setting} failed: {status}"));
+    }
+    Ok(())
+}
+
+pub async fn wrap(
+    path: &Path,
+    command: Vec<String>,
+    reserve_for_children: usize,
+    require_usage: bool,
+) -> ExitCode {
+    wrap_with_config(
+        path,
+        core::Config::from_env(),
+        command,
+        reserve_for_children,
+        require_usage,
+    )
+    .await
 }
 
-pub async fn wrap(path: &Path, command: Vec<String>) -> ExitCode {
-    let config = core::Config::from_env();
+/// Fail a successful run if `require_usage` is set and no database was ever leased.
+fn check_usage(exit_code: u8, leased: usize, require_usage: bool) -> u8 {
+    if exit_code == 0 && require_usage && leased == 0 {
+        error!("No database was leased during the run");
+        return 1;
+    }
+    exit_code
+}
+
+/// Exit code to pass on for a child, following the shell convention of
+/// `128 + signal` for children terminated by a signal.
+fn child_exit_code(status: ExitStatus) -> u8 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => u8::try_from(code).unwrap_or_else(|_| {
+            warn!("Exit code {} is out of range, exiting with 255", code);
+            u8::MAX
+        }),
+        (None, Some(signal)) => u8::try_from(128 + signal).unwrap_or(u8::MAX),
+        (None, None) => 1,
+    }
+}
+
+/// Lease `count` databases from the server at `path`, held until the guards are dropped.
+async fn reserve_databases(path: &Path, count: usize) -> Vec<DatabaseGuard> {
+    let mut guards = Vec::with_capacity(count);
+    for _ in 0..count {
+        guards.push(crate::get_database_at(path).await);
+    }
+    guards
+}
+
+async fn wrap_with_config(
+    path: &Path,
+    config: core::Config,
+    command: Vec<String>,
+    reserve_for_children: usize,
+    require_usage: bool,
+) -> ExitCode {
+    if reserve_for_children > config.max_databases() {
+        panic!(
+            "Cannot reserve {} databases from a pool of {}",
+            reserve_for_children,
+            config.max_databases()
+        );
+    }
     let (server, cancellation_token) = core::start_server(path, config).await;
+    let reserved = reserve_databases(path, reserve_for_children).await;
 
-    // Run the command as passed and send PGMANAGER_SOCKET env var
+    // Run the command as passed and send the socket path, under the deprecated
+    // PGMANAGER_SOCKET as well for clients that only know that name
     let (program, args) = command.split_first().expect("No command provided");
     let mut cmd = tokio::process::Command::new(program);
     cmd.args(args);
+    cmd.env("PGM_SOCKET", path.to_str().unwrap());
     cmd.env("PGMANAGER_SOCKET", path.to_str().unwrap());
+    for (n, db_name) in reserved.iter().enumerate() {
+        cmd.env(format!("PGM_DATABASE_{n}"), &db_name.name);
+    }
     let status = cmd.status().await.unwrap();
+    drop(reserved);
     cancellation_token.cancel();
-    server.await.unwrap();
-    let exit_code: u8 = status.code().unwrap_or(1).try_into().unwrap();
-    ExitCode::from(exit_code)
+    let stats = server.await.unwrap();
+    ExitCode::from(check_usage(
+        child_exit_code(status),
+        stats.peak_usage(),
+        require_usage,
+    ))
+}
+
+/// Quote `value` for a POSIX shell
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Shell exports for the database leased by `db_guard`
+fn env_exports(db_guard: &DatabaseGuard) -> String {
+    format!("export PGDATABASE={}\n", shell_quote(db_guard))
+}
+
+/// Lease a database from the server at `path` and print it as shell exports,
+/// holding the lease until interrupted or for `release_after`.
+pub async fn env(path: &Path, release_after: Option<Duration>) -> ExitCode {
+    let db_guard = crate::get_database_at(path).await;
+    print!("{}", env_exports(&db_guard));
+    std::io::stdout().flush().ok();
+
+    let release_after = async {
+        match release_after {
+            Some(release_after) => tokio::time::sleep(release_after).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = release_after => {}
+    }
+    drop(db_guard);
+    ExitCode::SUCCESS
+}
+
+/// Lease a database from the server at `path`, run `sql` against it and print the rows
+#[cfg(feature = "postgres")]
+pub async fn query(path: &Path, sql: &str, format: OutputFormat) -> ExitCode {
+    let db_guard = crate::get_database_at(path).await;
+    match crate::query::run(&db_guard, sql).await {
+        Ok(result) => {
+            print!("{}", result.format(format));
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("Query failed on {}: {}", db_guard, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Where `snapshot` writes the dump of `name` and `restore` reads it from
+fn snapshot_file(name: &str, file: Option<PathBuf>) -> PathBuf {
+    file.unwrap_or_else(|| PathBuf::from(format!("tmp/{name}.dump")))
+}
+
+/// Dump the reference database `name` with `pg_dump`, for `restore` to load into the pool
+pub async fn snapshot(name: &str, file: Option<PathBuf>) -> ExitCode {
+    let file = snapshot_file(name, file);
+    if let Some(parent) = file.parent()
+        && let Err(e) = std::fs::create_dir_all(parent)
+    {
+        error!("Failed to create {}: {}", parent.display(), e);
+        return ExitCode::FAILURE;
+    }
+    let status = tokio::process::Command::new("pg_dump")
+        .arg("--format=custom")
+        .arg("--file")
+        .arg(&file)
+        .arg(name)
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => {
+            info!("Wrote snapshot of {} to {}", name, file.display());
+            ExitCode::SUCCESS
+        }
+        Ok(status) => {
+            error!("pg_dump failed for {}: {}", name, status);
+            ExitCode::from(child_exit_code(status))
+        }
+        Err(e) => {
+            error!("Failed to run pg_dump: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Load the snapshot of `name` into every database in the pool with `pg_restore`
+pub async fn restore(name: &str, file: Option<PathBuf>) -> ExitCode {
+    restore_with_config(core::Config::from_env(), &snapshot_file(name, file)).await
+}
+
+async fn restore_with_config(config: core::Config, file: &Path) -> ExitCode {
+    if !file.is_file() {
+        error!("No snapshot at {}", file.display());
+        return ExitCode::FAILURE;
+    }
+    let databases: Vec<String> = core::build_databases(config)
+        .lock()
+        .await
+        .iter()
+        .cloned()
+        .collect();
+    let mut failed = 0;
+    for db_name in &databases {
+        let status = tokio::process::Command::new("pg_restore")
+            .args([
+                "--clean",
+                "--if-exists",
+                "--no-owner",
+                "--single-transaction",
+            ])
+            .arg("--dbname")
+            .arg(db_name)
+            .arg(file)
+            .status()
+            .await;
+        match status {
+            Ok(status) if status.success() => info!("Restored {}", db_name),
+            Ok(status) => {
+                error!("pg_restore failed for {}: {}", db_name, status);
+                failed += 1;
+            }
+            Err(e) => {
+                error!("Failed to run pg_restore for {}: {}", db_name, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        error!(
+            "Restore failed on {} of {} databases",
+            failed,
+            databases.len()
+        );
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Force-release the lease on `name` held by a client of the server at `path`
+pub async fn kill_lease(path: &Path, name: &str) -> ExitCode {
+    match crate::kill_lease_at(path, name).await {
+        Ok(()) => {
+            info!("Killed lease on {}", name);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("Failed to kill lease: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Shut down the server at `path`, waiting for it to exit if `wait` is set
+pub async fn shutdown(path: &Path, wait: bool) -> ExitCode {
+    if let Err(e) = crate::shutdown_at(path).await {
+        error!("Failed to shut down server: {}", e);
+        return ExitCode::FAILURE;
+    }
+    if wait {
+        info!("Waiting for the server on {} to exit...", path.display());
+        // The server removes its socket once it has finished shutting down
+        while path.exists() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Print the usage of the server at `path`, one `key=value` per line,
+/// resetting its peak and wait counters afterwards if `reset` is set.
+pub async fn stats(path: &Path, reset: bool) -> ExitCode {
+    let snapshot = crate::server_stats_at(path, reset).await;
+    for item in snapshot.to_string().split(';') {
+        println!("{item}");
+    }
+    ExitCode::SUCCESS
+}
+
+/// Parse a JSON object of shard index to database name, e.g. `{"0": "myapp_test3"}`
+fn parse_shard_map(shard_map: &str) -> Result<BTreeMap<usize, String>, String> {
+    let entries: BTreeMap<String, String> =
+        serde_json::from_str(shard_map).map_err(|e| format!("Invalid shard map: {e}"))?;
+    entries
+        .into_iter()
+        .map(|(shard, db_name)| match shard.parse() {
+            Ok(shard) => Ok((shard, db_name)),
+            Err(_) => Err(format!("Invalid shard index in shard map: {shard:?}")),
+        })
+        .collect()
 }
 
-pub async fn wrap_each(
+/// Order `databases` so that every shard in `shard_map` gets its pinned database,
+/// filling the remaining shards with the unpinned databases in build order.
+fn assign_shards(
+    databases: &[String],
+    shard_map: &BTreeMap<usize, String>,
+) -> Result<Vec<String>, String> {
+    let mut pinned: BTreeMap<usize, &String> = BTreeMap::new();
+    for (&shard, db_name) in shard_map {
+        if shard >= databases.len() {
+            return Err(format!(
+                "Shard {shard} is out of range for a pool of {}",
+                databases.len()
+            ));
+        }
+        if !databases.contains(db_name) {
+            return Err(format!(
+                "Database {db_name} for shard {shard} is not in the pool"
+            ));
+        }
+        if pinned.values().any(|&pinned| pinned == db_name) {
+            return Err(format!(
+                "Database {db_name} is mapped to more than one shard"
+            ));
+        }
+        pinned.insert(shard, db_name);
+    }
+
+    let mut unpinned = databases
+        .iter()
+        .filter(|db_name| !pinned.values().any(|&pinned| pinned == *db_name));
+    Ok((0..databases.len())
+        .map(|shard| match pinned.get(&shard) {
+            Some(&db_name) => db_name.clone(),
+            None => unpinned.next().expect("Every shard has a database").clone(),
+        })
+        .collect())
+}
+
+/// Flags for [`wrap_each`]
+#[derive(Debug, Default)]
+pub struct WrapEachOptions {
+    /// Keep going when a shard's command fails
+    pub ignore_exit_code: bool,
+    /// Pass the database name as an argument
+    pub xarg: bool,
+    /// JSON object pinning databases to shards, e.g. `{"0": "myapp_test3"}`
+    pub shard_map: Option<String>,
+    /// Fail if no shard ran and no database was leased during the run
+    pub require_usage: bool,
+    /// Where to write a JSON summary of every shard that ran
+    pub report: Option<PathBuf>,
+}
+
+pub async fn wrap_each(path: &Path, command: Vec<String>, options: WrapEachOptions) -> ExitCode {
+    wrap_each_with_config(
+        path,
+        core::Config::from_env_for_wrap_each(),
+        command,
+        options,
+    )
+    .await
+}
+
+async fn wrap_each_with_config(
     path: &Path,
+    config: core::Config,
     command: Vec<String>,
-    ignore_exit_code: bool,
-    xarg: bool,
+    options: WrapEachOptions,
 ) -> ExitCode {
-    let config = core::Config::from_env();
-    let (server, cancellation_token) = core::start_server(path, config.clone()).await;
+    let shard_map = match options
+        .shard_map
+        .as_deref()
+        .map(parse_shard_map)
+        .transpose()
+    {
+        Ok(shard_map) => shard_map.unwrap_or_default(),
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let databases: Vec<String> = core::build_databases(config.clone())
+        .lock()
+        .await
+        .iter()
+        .cloned()
+        .collect();
+    let databases = match assign_shards(&databases, &shard_map) {
+        Ok(databases) => databases,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let (server, cancellation_token) = core::start_server(path, config).await;
     let (program, args) = command.split_first().expect("No command provided");
-    let databases = core::build_databases(config);
     let mut exit_code: u8 = 0;
+    let mut report = Vec::new();
 
-    for (n, db_name) in databases.lock().await.iter().enumerate() {
+    for (n, db_name) in databases.iter().enumerate() {
         let mut cmd = tokio::process::Command::new(program);
         cmd.args(args);
-        if xarg {
+        if options.xarg {
             cmd.arg(db_name);
         }
         cmd.env("PGDATABASE", db_name);
         cmd.env("PGM_DATABASE_SHARD", n.to_string());
+        let started = Instant::now();
         let status = cmd.status().await.unwrap();
-        if !ignore_exit_code && !status.success() {
-            exit_code = status
-                .code()
-                .unwrap_or(1)
-                .try_into()
-                .expect("Unable to convert exit code to u8");
+        report.push(serde_json::json!({
+            "shard": n,
+            "database": db_name,
+            "exit_code": child_exit_code(status),
+            "duration_ms": started.elapsed().as_millis() as u64,
+        }));
+        if status.success() {
+            continue;
+        }
+        if options.ignore_exit_code {
+            warn!("Command failed for {}: {}", db_name, status);
+        } else {
+            error!("Command failed for {}: {}", db_name, status);
+            exit_code = child_exit_code(status);
             break;
         }
     }
     cancellation_token.cancel();
-    server.await.unwrap();
-    ExitCode::from(exit_code)
+    let stats = server.await.unwrap();
+    // Each shard's database is handed out directly, so it counts as a lease
+    let leased = stats.peak_usage() + report.len();
+    if let Some(path) = &options.report
+        && let Err(e) = std::fs::write(path, format!("{}\n", serde_json::Value::from(report)))
+    {
+        error!("Failed to write report to {}: {}", path.display(), e);
+        exit_code = exit_code.max(1);
+    }
+    ExitCode::from(check_usage(exit_code, leased, options.require_usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+
+    #[tokio::test]
+    async fn test_serve_exits_when_socket_in_use() {
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) = test_helpers::temp_server(&path, None).await;
+
+        let config = core::Config::new(1, "test_db_".to_string());
+        let exit_code = serve_with_config(&path, config, false).await;
+        assert_eq!(exit_code, ExitCode::from(EXIT_SOCKET_IN_USE));
+
+        // The running server is unaffected
+        let db_guard = crate::get_database_at(&path).await;
+        assert!(db_guard.name.starts_with("test_db_"));
+        drop(db_guard);
+        cancellation_token.cancel();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_serve() {
+        let path = test_helpers::temp_path();
+        let serving = {
+            let path = path.clone();
+            let config = core::Config::new(1, "test_db_".to_string());
+            tokio::spawn(async move { serve_with_config(&path, config, false).await })
+        };
+        crate::wait_until_listening(&path, Duration::from_secs(1))
+            .await
+            .expect("Server did not start listening");
+
+        assert_eq!(shutdown(&path, true).await, ExitCode::SUCCESS);
+        assert!(!path.exists());
+        let exit_code = tokio::time::timeout(Duration::from_secs(1), serving)
+            .await
+            .expect("serve did not exit after shutdown")
+            .unwrap();
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+    }
+
+    #[tokio::test]
+    async fn test_serve_commands_run_around_the_server() {
+        let path = test_helpers::temp_path();
+        let output = test_helpers::temp_path();
+        // Each records itself only while the socket doesn't exist
+        let record = |step: &str| {
+            format!(
+                "test ! -e '{}' && echo {} >> '{}'",
+                path.display(),
+                step,
+                output.display()
+            )
+        };
+        let config = core::Config::new(1, "test_db_".to_string())
+            .with_pre_serve_command(record("pre"))
+            .with_post_serve_command(record("post"));
+        let serving = {
+            let path = path.clone();
+            tokio::spawn(async move { serve_with_config(&path, config, false).await })
+        };
+        crate::wait_until_listening(&path, Duration::from_secs(1))
+            .await
+            .expect("Server did not start listening");
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "pre\n");
+
+        assert_eq!(shutdown(&path, false).await, ExitCode::SUCCESS);
+        assert_eq!(serving.await.unwrap(), ExitCode::SUCCESS);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "pre\npost\n");
+        std::fs::remove_file(output).unwrap();
+
+        let config = core::Config::new(1, "test_db_".to_string())
+            .with_pre_serve_command("exit 1".to_string());
+        assert_eq!(
+            serve_with_config(&path, config, false).await,
+            ExitCode::FAILURE
+        );
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_env_exports() {
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(1, "test_db".to_string()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+
+        let db_guard = crate::get_database_at(&path).await;
+        assert_eq!(env_exports(&db_guard), "export PGDATABASE='test_db0'\n");
+        drop(db_guard);
+        cancellation_token.cancel();
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[tokio::test]
+    async fn test_wrap_exports_reserved_databases() {
+        let path = test_helpers::temp_path();
+        let config = core::Config::new(3, "test_db_".to_string());
+        let command = [
+            "sh",
+            "-c",
+            r#"test -n "$PGM_DATABASE_0" && test -n "$PGM_DATABASE_1" && test -z "$PGM_DATABASE_2""#,
+        ];
+        let exit_code = wrap_with_config(
+            &path,
+            config,
+            command.iter().map(|s| s.to_string()).collect(),
+            2,
+            false,
+        )
+        .await;
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+    }
+
+    /// The command `test_wrap_with_strict_env` wraps
+    #[tokio::test]
+    #[ignore = "run inside pgmanager wrap by test_wrap_with_strict_env"]
+    async fn wrapped_get_database() {
+        if std::env::var_os("PGM_TEST_WRAPPED").is_none() {
+            return;
+        }
+        let db_guard = crate::get_database().await;
+        assert!(db_guard.name.starts_with("test_db_"));
+    }
+
+    #[tokio::test]
+    async fn test_wrap_with_strict_env() {
+        let path = test_helpers::temp_path();
+        let config = core::Config::new(1, "test_db_".to_string());
+        let test_binary = std::env::current_exe().unwrap();
+        let command = [
+            "env",
+            "PGM_STRICT_ENV=true",
+            "PGM_TEST_WRAPPED=1",
+            test_binary.to_str().unwrap(),
+            "commands::tests::wrapped_get_database",
+            "--exact",
+            "--ignored",
+            "--quiet",
+        ];
+        let exit_code = wrap_with_config(
+            &path,
+            config,
+            command.iter().map(|s| s.to_string()).collect(),
+            0,
+            true,
+        )
+        .await;
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+    }
+
+    #[tokio::test]
+    async fn test_wrap_signal_exit_code() {
+        let path = test_helpers::temp_path();
+        let config = core::Config::new(1, "test_db_".to_string());
+        let command = ["sh", "-c", "kill -KILL $$"];
+        let exit_code = wrap_with_config(
+            &path,
+            config,
+            command.iter().map(|s| s.to_string()).collect(),
+            0,
+            false,
+        )
+        .await;
+        assert_eq!(exit_code, ExitCode::from(128 + 9));
+    }
+
+    #[tokio::test]
+    async fn test_require_usage() {
+        let command: Vec<_> = ["true"].iter().map(|s| s.to_string()).collect();
+        let config = core::Config::new(1, "test_db_".to_string());
+        let exit_code =
+            wrap_with_config(&test_helpers::temp_path(), config, command.clone(), 0, true).await;
+        assert_eq!(exit_code, ExitCode::FAILURE);
+
+        let config = core::Config::new(1, "test_db_".to_string());
+        let exit_code = wrap_with_config(
+            &test_helpers::temp_path(),
+            config,
+            command.clone(),
+            0,
+            false,
+        )
+        .await;
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+
+        let config = core::Config::new(1, "test_db_".to_string());
+        let exit_code =
+            wrap_with_config(&test_helpers::temp_path(), config, command, 1, true).await;
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+    }
+
+    #[tokio::test]
+    async fn test_wrap_each_require_usage() {
+        let command: Vec<_> = ["true"].iter().map(|s| s.to_string()).collect();
+        let config = core::Config::new(2, "test_db_".to_string());
+        let options = WrapEachOptions {
+            require_usage: true,
+            ..Default::default()
+        };
+        let exit_code =
+            wrap_each_with_config(&test_helpers::temp_path(), config, command.clone(), options)
+                .await;
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+
+        let config = core::Config::new(0, "test_db_".to_string());
+        let options = WrapEachOptions {
+            require_usage: true,
+            ..Default::default()
+        };
+        let exit_code =
+            wrap_each_with_config(&test_helpers::temp_path(), config, command, options).await;
+        assert_eq!(exit_code, ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn test_child_exit_code() {
+        assert_eq!(child_exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(child_exit_code(ExitStatus::from_raw(15)), 128 + 15);
+    }
+
+    #[tokio::test]
+    async fn test_wrap_each_shard_map() {
+        let path = test_helpers::temp_path();
+        let output = test_helpers::temp_path();
+        let config = core::Config::new(3, "test_db_".to_string());
+        let shard_map = r#"{"0": "test_db_2", "2": "test_db_0"}"#.to_string();
+        let command = [
+            "sh",
+            "-c",
+            r#"echo "$PGM_DATABASE_SHARD=$PGDATABASE" >> "$0""#,
+            output.to_str().unwrap(),
+        ];
+        let exit_code = wrap_each_with_config(
+            &path,
+            config,
+            command.iter().map(|s| s.to_string()).collect(),
+            WrapEachOptions {
+                shard_map: Some(shard_map),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+
+        let contents = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(contents, "0=test_db_2\n1=test_db_1\n2=test_db_0\n");
+        std::fs::remove_file(output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wrap_each_report() {
+        let path = test_helpers::temp_path();
+        let report = test_helpers::temp_path();
+        let config = core::Config::new(3, "test_db_".to_string());
+        let command = ["sh", "-c", r#"exit "$PGM_DATABASE_SHARD""#];
+        let exit_code = wrap_each_with_config(
+            &path,
+            config,
+            command.iter().map(|s| s.to_string()).collect(),
+            WrapEachOptions {
+                ignore_exit_code: true,
+                report: Some(report.clone()),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+
+        let contents = std::fs::read_to_string(&report).unwrap();
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&contents).unwrap();
+        assert_eq!(entries.len(), 3);
+        for (n, entry) in entries.iter().enumerate() {
+            assert_eq!(entry["shard"], n);
+            assert_eq!(entry["database"], format!("test_db_{n}"));
+            assert_eq!(entry["exit_code"], n);
+            assert!(entry["duration_ms"].is_u64());
+        }
+        std::fs::remove_file(report).unwrap();
+    }
+
+    #[test]
+    fn test_wrap_each_logs_failed_databases() {
+        let run = |ignore_exit_code| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let mut exit_code = None;
+            let logs = test_helpers::capture_logs(|| {
+                let config = core::Config::new(3, "test_db_".to_string());
+                let command = ["sh", "-c", r#"test "$PGM_DATABASE_SHARD" != 1"#];
+                exit_code = Some(runtime.block_on(wrap_each_with_config(
+                    &test_helpers::temp_path(),
+                    config,
+                    command.iter().map(|s| s.to_string()).collect(),
+                    WrapEachOptions {
+                        ignore_exit_code,
+                        ..Default::default()
+                    },
+                )));
+            });
+            (exit_code.unwrap(), logs)
+        };
+
+        let (exit_code, logs) = run(true);
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+        assert!(logs.contains("WARN"), "{logs}");
+        assert!(logs.contains("Command failed for test_db_1"), "{logs}");
+        assert!(!logs.contains("Command failed for test_db_0"), "{logs}");
+
+        let (exit_code, logs) = run(false);
+        assert_eq!(exit_code, ExitCode::from(1));
+        assert!(logs.contains("ERROR"), "{logs}");
+        assert!(logs.contains("Command failed for test_db_1"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_restore_without_snapshot() {
+        let config = core::Config::new(1, "test_db_".to_string());
+        let missing = test_helpers::temp_path();
+        assert_eq!(
+            restore_with_config(config, &missing).await,
+            ExitCode::FAILURE
+        );
+    }
+
+    /// Needs a reachable postgres where `PGUSER` may create databases, plus `pg_dump` and `pg_restore`
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[ignore = "requires a running postgres"]
+    async fn test_snapshot_and_restore() {
+        async fn count(db_name: &str) -> String {
+            crate::query::run(db_name, "SELECT count(*) FROM fixtures")
+                .await
+                .unwrap()
+                .format(OutputFormat::Csv)
+        }
+
+        let prefix = format!("pgm_test_{:x}_", crate::util::random_u64());
+        let reference = format!("{prefix}reference");
+        let pool: Vec<_> = (0..2).map(|n| format!("{prefix}{n}")).collect();
+        let all: Vec<_> = pool.iter().chain([&reference]).cloned().collect();
+        crate::query::create_databases(&all, "CREATE DATABASE {name}")
+            .await
+            .unwrap();
+        crate::query::execute(
+            &reference,
+            "CREATE TABLE fixtures (id int); INSERT INTO fixtures SELECT generate_series(1, 3);",
+        )
+        .await
+        .unwrap();
+        // Stale rows in the pool are replaced
+        crate::query::execute(
+            &pool[0],
+            "CREATE TABLE fixtures (id int); INSERT INTO fixtures SELECT generate_series(1, 5);",
+        )
+        .await
+        .unwrap();
+
+        let file = test_helpers::temp_path();
+        assert_eq!(
+            snapshot(&reference, Some(file.clone())).await,
+            ExitCode::SUCCESS
+        );
+        let config = core::Config::new(2, prefix.clone());
+        assert_eq!(restore_with_config(config, &file).await, ExitCode::SUCCESS);
+        for db_name in &pool {
+            assert_eq!(count(db_name).await, count(&reference).await);
+        }
+        assert!(count(&pool[0]).await.ends_with("\n3\n"));
+
+        std::fs::remove_file(file).unwrap();
+        crate::query::drop_databases(&all).await.unwrap();
+    }
+
+    #[test]
+    fn test_assign_shards_rejects_unknown_database() {
+        let databases = vec!["test_db_0".to_string(), "test_db_1".to_string()];
+        let shard_map = parse_shard_map(r#"{"1": "other_db"}"#).unwrap();
+        let err = assign_shards(&databases, &shard_map).unwrap_err();
+        assert_eq!(err, "Database other_db for shard 1 is not in the pool");
+        assert!(parse_shard_map(r#"{"first": "test_db_0"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reserved_databases_are_released_on_drop() {
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(2, "test_db_".to_string()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+
+        let reserved = reserve_databases(&path, 2).await;
+        let mut names: Vec<_> = reserved.iter().map(|db| db.name.clone()).collect();
+        names.sort();
+        assert_eq!(names, vec!["test_db_0", "test_db_1"]);
+        drop(reserved);
+
+        let reacquired = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            reserve_databases(&path, 2),
+        )
+        .await
+        .expect("Reserved databases were not released");
+        assert_eq!(reacquired.len(), 2);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
 }
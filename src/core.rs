@@ -1,20 +1,68 @@
-use std::{collections::VecDeque, path::Path, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    os::unix::fs::FileTypeExt as _,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt as _},
+    io::AsyncWriteExt as _,
     net::{UnixListener, UnixStream, unix::SocketAddr},
     select,
     sync::Mutex,
 };
-use tokio_util::sync::CancellationToken;
-use tracing::{debug, info};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{Instrument as _, debug, error, info, warn};
 
-use crate::{stats, util};
+use crate::{
+    protocol::{self, AcquireOptions, Request},
+    stats, util,
+};
 
 #[derive(Clone)]
 pub(crate) struct Config {
     max_databases: usize,
     prefix: String,
+    /// Appended to every database name to keep managers sharing a prefix apart
+    instance_id: Option<String>,
+    /// How long shutdown waits for leases to be returned before closing them
+    drain_timeout: Option<Duration>,
+    /// Where to write the worker index to database mapping once the pool is built
+    assignment_file: Option<PathBuf>,
+    /// Databases only handed out to priority requests
+    reserve_free: usize,
+    /// Capability tags by database index
+    capabilities: BTreeMap<usize, BTreeSet<String>>,
+    /// Connections beyond this many are refused
+    max_connections: Option<usize>,
+    /// How often a waiting acquire checks for a free database
+    wait_poll: Duration,
+    /// How many clients are expected to hold a database at once
+    expected_concurrency: Option<usize>,
+    /// Lease databases to lock files created in this directory
+    lock_dir: Option<PathBuf>,
+    /// Only accept connections from processes running as these uids
+    allowed_peer_uids: Option<BTreeSet<u32>>,
+    /// Create the pool's databases with this SQL at startup and drop them at shutdown
+    create_db_sql: Option<String>,
+    /// How long a client process that just released a database must wait to get another
+    reacquire_cooldown: Option<Duration>,
+    /// Run against every database at startup, aborting if it fails on any of them
+    startup_validate_sql: Option<String>,
+    /// Shell command `serve` runs before building the pool
+    pre_serve_command: Option<String>,
+    /// Shell command `serve` runs after shutting down
+    post_serve_command: Option<String>,
+    /// How a database is cleaned before it goes back into the pool
+    reset: ResetStrategy,
+    /// Hand out databases strictly by build order, one acquire at a time
+    deterministic: bool,
+    /// Apply the `.sql` files in this directory to every database at startup
+    migrations_dir: Option<PathBuf>,
+    /// Lease a database to connections that send no request, as clients before 0.4 do
+    legacy_clients: bool,
 }
 
 impl Config {
@@ -22,63 +70,1057 @@ impl Config {
         Self {
             max_databases,
             prefix,
+            instance_id: None,
+            drain_timeout: None,
+            assignment_file: None,
+            reserve_free: 0,
+            capabilities: BTreeMap::new(),
+            max_connections: None,
+            wait_poll: Duration::from_millis(10),
+            expected_concurrency: None,
+            lock_dir: None,
+            allowed_peer_uids: None,
+            create_db_sql: None,
+            reacquire_cooldown: None,
+            startup_validate_sql: None,
+            pre_serve_command: None,
+            post_serve_command: None,
+            reset: ResetStrategy::None,
+            deterministic: false,
+            migrations_dir: None,
+            legacy_clients: false,
         }
     }
 
     pub(crate) fn from_env() -> Self {
+        Self::read_env()
+            .validate()
+            .and_then(Self::validate_instance_suffix)
+            .unwrap_or_else(|err| panic!("Invalid configuration: {err}"))
+    }
+
+    /// Like [`Config::from_env`], but `wrap-each` commands may create suffixed databases themselves.
+    pub(crate) fn from_env_for_wrap_each() -> Self {
+        Self::read_env()
+            .validate()
+            .unwrap_or_else(|err| panic!("Invalid configuration: {err}"))
+    }
+
+    fn read_env() -> Self {
         let max_databases: usize = util::env_var("DATABASE_COUNT").unwrap_or(8);
-        let prefix: String = util::env_var("DATABASE_PREFIX").expect("DATABASE_PREFIX must be set");
-        Self::new(max_databases, prefix)
+        let prefix: String = match util::optional_env_var::<PathBuf>("PREFIX_FILE") {
+            Some(path) => read_prefix_file(&path).unwrap_or_else(|err| panic!("{err}")),
+            None => util::env_var("DATABASE_PREFIX").expect("DATABASE_PREFIX must be set"),
+        };
+        let mut config = Self::new(max_databases, prefix);
+        if util::optional_env_var("INSTANCE_SUFFIX").unwrap_or(false) {
+            config = config.with_instance_suffix();
+        }
+        if util::optional_env_var("LEGACY_CLIENTS").unwrap_or(false) {
+            config = config.with_legacy_clients();
+        }
+        if util::optional_env_var("DETERMINISTIC").unwrap_or(false) {
+            config = config.with_deterministic();
+        }
+        if let Some(ms) = util::optional_env_var("DRAIN_TIMEOUT_MS") {
+            config = config.with_drain_timeout(Duration::from_millis(ms));
+        }
+        if let Some(path) = util::optional_env_var("ASSIGNMENT_FILE") {
+            config = config.with_assignment_file(path);
+        }
+        if let Some(reserve_free) = util::optional_env_var("RESERVE_FREE") {
+            config = config.with_reserve_free(reserve_free);
+        }
+        if let Some(capabilities) = util::optional_env_var::<String>("DATABASE_CAPABILITIES") {
+            config = config.with_capabilities(
+                parse_capabilities(&capabilities).unwrap_or_else(|err| panic!("{err}")),
+            );
+        }
+        if let Some(max_connections) = util::optional_env_var("MAX_CONNECTIONS") {
+            config = config.with_max_connections(max_connections);
+        }
+        if let Some(ms) = util::optional_env_var("WAIT_POLL_MS") {
+            config = config.with_wait_poll(Duration::from_millis(ms));
+        }
+        if let Some(ms) = util::optional_env_var("REACQUIRE_COOLDOWN_MS") {
+            config = config.with_reacquire_cooldown(Duration::from_millis(ms));
+        }
+        if let Some(expected_concurrency) = util::optional_env_var("EXPECTED_CONCURRENCY") {
+            config = config.with_expected_concurrency(expected_concurrency);
+        }
+        if let Some(lock_dir) = util::optional_env_var("LOCK_DIR") {
+            config = config.with_lock_dir(lock_dir);
+        }
+        if let Some(sql) = util::optional_env_var("CREATE_DB_SQL") {
+            config = config.with_create_db_sql(sql);
+        }
+        if let Some(dir) = util::optional_env_var("MIGRATIONS_DIR") {
+            config = config.with_migrations_dir(dir);
+        }
+        if let Some(sql) = util::optional_env_var("STARTUP_VALIDATE_SQL") {
+            config = config.with_startup_validate_sql(sql);
+        }
+        if let Some(command) = util::optional_env_var("PRE_SERVE_COMMAND") {
+            config = config.with_pre_serve_command(command);
+        }
+        if let Some(command) = util::optional_env_var("POST_SERVE_COMMAND") {
+            config = config.with_post_serve_command(command);
+        }
+        if let Some(reset) = util::optional_env_var::<String>("DATABASE_RESET") {
+            config = config.with_reset(reset.parse().unwrap_or_else(|err| panic!("{err}")));
+        }
+        if let Some(uids) = util::optional_env_var::<String>("RESTRICT_PEER_UID") {
+            let uids = parse_peer_uids(&uids).unwrap_or_else(|err| panic!("{err}"));
+            if let Some(uids) = uids {
+                config = config.with_allowed_peer_uids(uids);
+            }
+        }
+        config
+    }
+
+    pub(crate) fn with_instance_suffix(mut self) -> Self {
+        let instance_id = format!("{:06x}", util::random_u64() & 0xff_ffff);
+        info!("Using instance id: {}", instance_id);
+        self.instance_id = Some(instance_id);
+        self
+    }
+
+    pub(crate) fn with_legacy_clients(mut self) -> Self {
+        self.legacy_clients = true;
+        self
+    }
+
+    pub(crate) fn with_deterministic(mut self) -> Self {
+        self.deterministic = true;
+        self
+    }
+
+    pub(crate) fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = Some(drain_timeout);
+        self
+    }
+
+    pub(crate) fn with_assignment_file(mut self, path: PathBuf) -> Self {
+        self.assignment_file = Some(path);
+        self
+    }
+
+    pub(crate) fn with_reserve_free(mut self, reserve_free: usize) -> Self {
+        self.reserve_free = reserve_free;
+        self
+    }
+
+    pub(crate) fn with_capabilities(
+        mut self,
+        capabilities: BTreeMap<usize, BTreeSet<String>>,
+    ) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub(crate) fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    pub(crate) fn with_wait_poll(mut self, wait_poll: Duration) -> Self {
+        self.wait_poll = wait_poll;
+        self
+    }
+
+    pub(crate) fn with_reacquire_cooldown(mut self, cooldown: Duration) -> Self {
+        self.reacquire_cooldown = Some(cooldown);
+        self
+    }
+
+    pub(crate) fn with_expected_concurrency(mut self, expected_concurrency: usize) -> Self {
+        self.expected_concurrency = Some(expected_concurrency);
+        self
+    }
+
+    pub(crate) fn with_lock_dir(mut self, lock_dir: PathBuf) -> Self {
+        self.lock_dir = Some(lock_dir);
+        self
+    }
+
+    pub(crate) fn with_allowed_peer_uids(mut self, uids: BTreeSet<u32>) -> Self {
+        self.allowed_peer_uids = Some(uids);
+        self
+    }
+
+    pub(crate) fn with_create_db_sql(mut self, sql: String) -> Self {
+        self.create_db_sql = Some(sql);
+        self
+    }
+
+    pub(crate) fn with_migrations_dir(mut self, dir: PathBuf) -> Self {
+        self.migrations_dir = Some(dir);
+        self
+    }
+
+    pub(crate) fn with_startup_validate_sql(mut self, sql: String) -> Self {
+        self.startup_validate_sql = Some(sql);
+        self
+    }
+
+    pub(crate) fn with_pre_serve_command(mut self, command: String) -> Self {
+        self.pre_serve_command = Some(command);
+        self
+    }
+
+    pub(crate) fn with_post_serve_command(mut self, command: String) -> Self {
+        self.post_serve_command = Some(command);
+        self
+    }
+
+    pub(crate) fn with_reset(mut self, reset: ResetStrategy) -> Self {
+        self.reset = reset;
+        self
     }
+
+    pub(crate) fn max_databases(&self) -> usize {
+        self.max_databases
+    }
+
+    pub(crate) fn pre_serve_command(&self) -> Option<&str> {
+        self.pre_serve_command.as_deref()
+    }
+
+    pub(crate) fn post_serve_command(&self) -> Option<&str> {
+        self.post_serve_command.as_deref()
+    }
+
+    pub(crate) fn validate(self) -> Result<Self, String> {
+        if self.max_databases == 0 {
+            return Err(
+                "DATABASE_COUNT must be at least 1, an empty pool never hands out a database"
+                    .to_string(),
+            );
+        }
+        if self.reserve_free >= self.max_databases {
+            return Err(format!(
+                "RESERVE_FREE ({}) must be less than DATABASE_COUNT ({})",
+                self.reserve_free, self.max_databases
+            ));
+        }
+        if let Some(sql) = &self.create_db_sql {
+            if !cfg!(feature = "postgres") {
+                return Err("CREATE_DB_SQL requires the postgres feature".to_string());
+            }
+            if !sql.contains("{name}") {
+                return Err("CREATE_DB_SQL must contain a {name} placeholder".to_string());
+            }
+        }
+        if self.migrations_dir.is_some() && !cfg!(feature = "postgres") {
+            return Err("MIGRATIONS_DIR requires the postgres feature".to_string());
+        }
+        if self.startup_validate_sql.is_some() && !cfg!(feature = "postgres") {
+            return Err("STARTUP_VALIDATE_SQL requires the postgres feature".to_string());
+        }
+        if self.reset != ResetStrategy::None && !cfg!(feature = "postgres") {
+            return Err("DATABASE_RESET requires the postgres feature".to_string());
+        }
+        if self.wait_poll.is_zero() {
+            return Err("WAIT_POLL_MS must be at least 1".to_string());
+        }
+        if let Some(n) = self.capabilities.keys().find(|&&n| n >= self.max_databases) {
+            return Err(format!(
+                "DATABASE_CAPABILITIES refers to database {} in a pool of {}",
+                n, self.max_databases
+            ));
+        }
+        Ok(self)
+    }
+
+    /// The instance id is new on every run, so only the server itself can create those databases.
+    fn validate_instance_suffix(self) -> Result<Self, String> {
+        if self.instance_id.is_some() && self.create_db_sql.is_none() {
+            return Err(
+                "INSTANCE_SUFFIX requires CREATE_DB_SQL, nothing else creates the suffixed databases"
+                    .to_string(),
+            );
+        }
+        Ok(self)
+    }
+}
+
+/// How a database is cleaned before it goes back into the pool, from `PGM_DATABASE_RESET`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum ResetStrategy {
+    /// Hand the database out again as the last client left it
+    #[default]
+    None,
+    /// Empty every table in the public schema
+    Truncate,
+    /// Drop and recreate the public schema
+    DropSchema,
 }
 
+impl ResetStrategy {
+    #[cfg(feature = "postgres")]
+    fn sql(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Truncate => Some(
+                "DO $$
+                DECLARE tables text;
+                BEGIN
+                    SELECT string_agg(format('%I.%I', schemaname, tablename), ', ') INTO tables
+                    FROM pg_tables WHERE schemaname = 'public';
+                    IF tables IS NOT NULL THEN
+                        EXECUTE 'TRUNCATE ' || tables || ' RESTART IDENTITY CASCADE';
+                    END IF;
+                END $$",
+            ),
+            Self::DropSchema => Some("DROP SCHEMA public CASCADE; CREATE SCHEMA public;"),
+        }
+    }
+}
+
+impl FromStr for ResetStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Self::None),
+            "truncate" => Ok(Self::Truncate),
+            "drop-schema" => Ok(Self::DropSchema),
+            _ => Err(format!(
+                "Invalid DATABASE_RESET: {value:?}, expected none, truncate or drop-schema"
+            )),
+        }
+    }
+}
+
+/// Parse a JSON object of database index to capability tags, e.g. `{"0": ["postgis"]}`
+fn parse_capabilities(capabilities: &str) -> Result<BTreeMap<usize, BTreeSet<String>>, String> {
+    let entries: BTreeMap<String, BTreeSet<String>> = serde_json::from_str(capabilities)
+        .map_err(|e| format!("Invalid DATABASE_CAPABILITIES: {e}"))?;
+    entries
+        .into_iter()
+        .map(|(n, tags)| match n.parse() {
+            Ok(n) => Ok((n, tags)),
+            Err(_) => Err(format!(
+                "Invalid database index in DATABASE_CAPABILITIES: {n:?}"
+            )),
+        })
+        .collect()
+}
+
+/// Parse `PGM_RESTRICT_PEER_UID`: `true` for the server's own uid, or a comma-separated uid list
+fn parse_peer_uids(value: &str) -> Result<Option<BTreeSet<u32>>, String> {
+    match value {
+        "false" => Ok(None),
+        "true" => Ok(Some([util::current_uid()].into())),
+        uids => uids
+            .split(',')
+            .map(|uid| {
+                uid.trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid uid in RESTRICT_PEER_UID: {uid:?}"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Some),
+    }
+}
+
+/// Read the database prefix from a file (or `/dev/stdin`) to keep it out of the environment.
+fn read_prefix_file(path: &Path) -> Result<String, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read prefix file {}: {}", path.display(), e))?;
+    let prefix = contents.trim_end_matches(['\r', '\n']);
+    if prefix.is_empty() {
+        return Err(format!("Prefix file {} is empty", path.display()));
+    }
+    Ok(prefix.to_string())
+}
+
+/// Resolves to the pool's usage once the server has shut down
+pub(crate) type ServerHandle = tokio::task::JoinHandle<Arc<stats::Stats>>;
+
 type Databases = Arc<Mutex<VecDeque<String>>>;
+/// Detached leases by token
+type Leases = Arc<Mutex<HashMap<String, String>>>;
+/// Cancelled to close the connection holding a database, by database name
+type Kills = Arc<Mutex<HashMap<String, CancellationToken>>>;
+/// Databases shared by connections presenting the same group token
+type Groups = Arc<Mutex<HashMap<String, Group>>>;
+/// When each client process last released a database, by pid
+type Releases = Arc<Mutex<HashMap<i32, Instant>>>;
+/// Acquires waiting in deterministic mode, by ticket
+type Waiters = Arc<std::sync::Mutex<BTreeMap<u64, AcquireOptions>>>;
 
-async fn respond(databases: Databases, mut stream: UnixStream, address: SocketAddr) {
-    tokio::spawn(async move {
-        debug!("New connection from {:?}", address);
-        debug!("Assigning database...");
-        let name = {
-            loop {
-                let mut dbs = databases.lock().await;
-                if let Some(name) = dbs.pop_front() {
-                    stats::increment_usage();
-                    break name.clone();
-                }
-                drop(dbs);
-                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-                stats::TOTAL_WAIT.fetch_add(10, std::sync::atomic::Ordering::Relaxed);
+#[derive(Default)]
+struct Group {
+    /// Set by whichever member acquires the database first
+    name: Arc<tokio::sync::OnceCell<String>>,
+    members: usize,
+}
+
+#[derive(Clone)]
+struct Pool {
+    databases: Databases,
+    leases: Leases,
+    kills: Kills,
+    groups: Groups,
+    stats: Arc<stats::Stats>,
+    reserve_free: usize,
+    /// Capability tags by database name
+    capabilities: Arc<HashMap<String, BTreeSet<String>>>,
+    max_connections: Option<usize>,
+    wait_poll: Duration,
+    allowed_peer_uids: Option<Arc<BTreeSet<u32>>>,
+    reacquire_cooldown: Option<Duration>,
+    releases: Releases,
+    /// Cancelled to shut the server down
+    shutdown: CancellationToken,
+    #[cfg(feature = "postgres")]
+    reset: ResetStrategy,
+    #[cfg(feature = "postgres")]
+    migrations: Arc<Vec<Migration>>,
+    /// Build index by database name, set in deterministic mode
+    build_order: Option<Arc<HashMap<String, usize>>>,
+    /// Earlier waiters that could take a database go first in deterministic mode
+    waiters: Waiters,
+    legacy_clients: bool,
+}
+
+impl Pool {
+    fn new(config: Config) -> Self {
+        let databases = build_databases(config.clone());
+        let capabilities = databases
+            .try_lock()
+            .expect("Pool is not shared yet")
+            .iter()
+            .enumerate()
+            .map(|(n, name)| {
+                let tags = config.capabilities.get(&n).cloned().unwrap_or_default();
+                (name.clone(), tags)
+            })
+            .collect();
+        let build_order = config.deterministic.then(|| {
+            let databases = databases.try_lock().expect("Pool is not shared yet");
+            Arc::new(databases.iter().cloned().zip(0..).collect())
+        });
+        Self {
+            reserve_free: config.reserve_free,
+            max_connections: config.max_connections,
+            wait_poll: config.wait_poll,
+            allowed_peer_uids: config.allowed_peer_uids.map(Arc::new),
+            reacquire_cooldown: config.reacquire_cooldown,
+            releases: Releases::default(),
+            shutdown: CancellationToken::new(),
+            #[cfg(feature = "postgres")]
+            reset: config.reset,
+            #[cfg(feature = "postgres")]
+            migrations: Arc::new(
+                config
+                    .migrations_dir
+                    .as_deref()
+                    .map(read_migrations)
+                    .transpose()
+                    .unwrap_or_else(|err| panic!("{err}"))
+                    .unwrap_or_default(),
+            ),
+            build_order,
+            waiters: Waiters::default(),
+            legacy_clients: config.legacy_clients,
+            capabilities: Arc::new(capabilities),
+            databases,
+            leases: Leases::default(),
+            kills: Kills::default(),
+            groups: Groups::default(),
+            stats: Arc::default(),
+        }
+    }
+
+    fn satisfies(&self, name: &str, options: &AcquireOptions) -> bool {
+        options.name.as_ref().is_none_or(|wanted| wanted == name)
+            && (options.capabilities.is_empty()
+                || self
+                    .capabilities
+                    .get(name)
+                    .is_some_and(|tags| tags.is_superset(&options.capabilities)))
+    }
+
+    /// Whether any database in the pool, leased or not, can satisfy `options`.
+    fn can_satisfy(&self, options: &AcquireOptions) -> bool {
+        self.capabilities
+            .keys()
+            .any(|name| self.satisfies(name, options))
+    }
+
+    /// How much longer `pid` must wait before it may acquire again, if it released too recently.
+    async fn cooldown_remaining(&self, pid: i32) -> Option<Duration> {
+        let cooldown = self.reacquire_cooldown?;
+        let released = *self.releases.lock().await.get(&pid)?;
+        cooldown
+            .checked_sub(released.elapsed())
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Clean `name` as configured with `PGM_DATABASE_RESET` and put it back in the pool.
+    async fn return_database(&self, name: String) {
+        #[cfg(feature = "postgres")]
+        if let Err(e) = self.reset_database(&name).await {
+            // Handing out a half-reset database would leak state between clients
+            error!("Keeping database {} out of the pool: {}", name, e);
+            self.stats.decrement_usage();
+            return;
+        }
+        self.databases.lock().await.push_back(name);
+        self.stats.decrement_usage();
+    }
+
+    #[cfg(feature = "postgres")]
+    async fn reset_database(&self, name: &str) -> Result<(), String> {
+        let Some(sql) = self.reset.sql() else {
+            return Ok(());
+        };
+        crate::query::execute(name, sql)
+            .await
+            .map_err(|e| format!("reset failed: {e}"))?;
+        debug!("Reset database {}", name);
+        // Dropping the schema took the migrations with it
+        if self.reset == ResetStrategy::DropSchema {
+            migrate_database(name, &self.migrations)
+                .await
+                .map_err(|e| format!("migration failed: {e}"))?;
+        }
+        Ok(())
+    }
+
+    async fn record_release(&self, pid: i32) {
+        let Some(cooldown) = self.reacquire_cooldown else {
+            return;
+        };
+        let mut releases = self.releases.lock().await;
+        releases.retain(|_, released| released.elapsed() < cooldown);
+        releases.insert(pid, Instant::now());
+    }
+}
+
+/// Outstanding client connections, so shutdown can wait for or close them
+#[derive(Clone, Default)]
+struct Connections {
+    tracker: TaskTracker,
+    force_close: CancellationToken,
+}
+
+fn lease_token() -> String {
+    format!("{:016x}", util::random_u64())
+}
+
+/// Where in `dbs` the database `options` would be given sits, if one is free to take.
+fn pick(pool: &Pool, dbs: &VecDeque<String>, options: &AcquireOptions) -> Option<usize> {
+    let reserve = if options.priority {
+        0
+    } else {
+        pool.reserve_free
+    };
+    if dbs.len() <= reserve {
+        return None;
+    }
+    let mut candidates = dbs
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| pool.satisfies(name, options));
+    match &pool.build_order {
+        Some(build_order) => candidates
+            .min_by_key(|(_, name)| build_order[*name])
+            .map(|(position, _)| position),
+        None => candidates.next().map(|(position, _)| position),
+    }
+}
+
+async fn try_acquire(pool: &Pool, options: &AcquireOptions) -> Option<String> {
+    try_acquire_before(pool, options, u64::MAX).await
+}
+
+/// Take a database for `options` unless a waiter with a ticket below `ticket` could take one now.
+async fn try_acquire_before(pool: &Pool, options: &AcquireOptions, ticket: u64) -> Option<String> {
+    let mut dbs = pool.databases.lock().await;
+    let waiters = pool.waiters.lock().expect("Waiters lock poisoned");
+    if waiters
+        .range(..ticket)
+        .any(|(_, earlier)| pick(pool, &dbs, earlier).is_some())
+    {
+        return None;
+    }
+    drop(waiters);
+    let position = pick(pool, &dbs, options)?;
+    let name = dbs.remove(position)?;
+    pool.stats.increment_usage();
+    Some(name)
+}
+
+/// A place in the deterministic mode queue, given up when dropped
+struct Ticket {
+    waiters: Waiters,
+    id: u64,
+}
+
+impl Ticket {
+    fn take(waiters: &Waiters, options: &AcquireOptions) -> Self {
+        let mut queue = waiters.lock().expect("Waiters lock poisoned");
+        let id = queue.last_key_value().map_or(0, |(id, _)| id + 1);
+        queue.insert(id, options.clone());
+        Self {
+            waiters: waiters.clone(),
+            id,
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if let Ok(mut queue) = self.waiters.lock() {
+            queue.remove(&self.id);
+        }
+    }
+}
+
+async fn acquire(pool: &Pool, options: &AcquireOptions) -> String {
+    // Blocked waiters only hold back later ones that want the same databases
+    let ticket = pool
+        .build_order
+        .is_some()
+        .then(|| Ticket::take(&pool.waiters, options));
+    let turn = ticket.as_ref().map_or(u64::MAX, |ticket| ticket.id);
+    loop {
+        if let Some(name) = try_acquire_before(pool, options, turn).await {
+            break name;
+        }
+        tokio::time::sleep(pool.wait_poll).await;
+        pool.stats.add_wait(pool.wait_poll.as_millis() as usize);
+    }
+}
+
+/// Lease a database to every `<key>.lock` file in `dir`, writing its name to
+/// `<key>.db`, and release it once the lock file is removed.
+async fn watch_lock_dir(pool: Pool, dir: PathBuf, cancellation_token: CancellationToken) {
+    // Mappings left behind by a previous server are stale
+    for db_file in files_with_extension(&dir, "db") {
+        std::fs::remove_file(db_file).ok();
+    }
+    let options = AcquireOptions::default();
+    let mut held: HashMap<PathBuf, String> = HashMap::new();
+    loop {
+        select! {
+            _ = cancellation_token.cancelled() => break,
+            _ = tokio::time::sleep(pool.wait_poll) => {}
+        }
+        let locks: HashSet<PathBuf> = files_with_extension(&dir, "lock").collect();
+        let released: Vec<_> = held
+            .keys()
+            .filter(|lock| !locks.contains(*lock))
+            .cloned()
+            .collect();
+        for lock in released {
+            let name = held.remove(&lock).expect("Lock is held");
+            debug!("Releasing database: {} for {}", name, lock.display());
+            std::fs::remove_file(lock.with_extension("db")).ok();
+            pool.return_database(name).await;
+        }
+        for lock in locks {
+            if held.contains_key(&lock) {
+                continue;
+            }
+            let Some(name) = try_acquire(&pool, &options).await else {
+                break;
+            };
+            debug!("Assigned database: {} to {}", name, lock.display());
+            if let Err(e) = write_lock_mapping(&lock.with_extension("db"), &name) {
+                error!("Failed to write database for {}: {}", lock.display(), e);
+            }
+            held.insert(lock, name);
+        }
+    }
+    for (lock, name) in held {
+        std::fs::remove_file(lock.with_extension("db")).ok();
+        pool.return_database(name).await;
+    }
+}
+
+fn files_with_extension(dir: &Path, extension: &str) -> impl Iterator<Item = PathBuf> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(move |path| path.extension().is_some_and(|ext| ext == extension))
+}
+
+/// Atomically write `name` to `path`, so clients never read a partial name.
+fn write_lock_mapping(path: &Path, name: &str) -> std::io::Result<()> {
+    let temp_path = path.with_extension("db.tmp");
+    std::fs::write(&temp_path, name)?;
+    std::fs::rename(&temp_path, path)
+}
+
+/// Send `response` as one line
+async fn write_response(stream: &mut UnixStream, response: String) {
+    write_bytes(stream, format!("{response}\n").as_bytes()).await;
+}
+
+async fn write_bytes(stream: &mut UnixStream, bytes: &[u8]) {
+    if let Err(e) = stream.write_all(bytes).await {
+        debug!("Failed to write to stream: {}", e);
+    }
+    if let Err(e) = stream.flush().await {
+        debug!("Failed to flush stream: {}", e);
+    }
+}
+
+/// Lease a database until the client returns it or disconnects. A `legacy` lease
+/// given to a client that then turns out to send a request returns the database
+/// and hands back the request to be handled instead.
+async fn lease(
+    pool: Pool,
+    mut stream: UnixStream,
+    mut options: AcquireOptions,
+    force_close: CancellationToken,
+    legacy: bool,
+) -> Option<(UnixStream, Request)> {
+    if let Some(name) = &options.name
+        && !pool.capabilities.contains_key(name)
+    {
+        debug!("No database named {:?} in the pool, assigning any", name);
+        options.name = None;
+    }
+    if !pool.can_satisfy(&options) {
+        debug!("No database matches {:?}", options);
+        let capabilities: Vec<_> = options.capabilities.iter().map(String::as_str).collect();
+        let message = format!("No database has capabilities {}", capabilities.join(", "));
+        write_response(&mut stream, format!("EMPTY:{}", message)).await;
+        return None;
+    }
+    if let Some(group) = options.group.clone() {
+        lease_shared(pool, stream, options, group, force_close).await;
+        return None;
+    }
+    debug!("Assigning database...");
+    let pid = stream.peer_cred().ok().and_then(|cred| cred.pid());
+    let cooldown = match pid {
+        Some(pid) => pool.cooldown_remaining(pid).await,
+        None => None,
+    };
+    let name = if options.nowait {
+        let name = match cooldown {
+            Some(_) => None,
+            None => try_acquire(&pool, &options).await,
+        };
+        let Some(name) = name else {
+            debug!("No database free for nowait request");
+            write_response(&mut stream, "EMPTY:No databases available".to_string()).await;
+            return None;
+        };
+        name
+    } else {
+        let acquire = async {
+            if let Some(cooldown) = cooldown {
+                debug!(
+                    "Client released a database recently, waiting {:?}",
+                    cooldown
+                );
+                tokio::time::sleep(cooldown).await;
+            }
+            acquire(&pool, &options).await
+        };
+        select! {
+            name = acquire => name,
+            _ = force_close.cancelled() => return None,
+        }
+    };
+    let instant = Instant::now();
+    // Respont to the client OK:{db_name} or EMPTY:No databases available
+    debug!("Assigned database: {:?}", name);
+    let killed = CancellationToken::new();
+    pool.kills.lock().await.insert(name.clone(), killed.clone());
+    if legacy {
+        // Clients before 0.4 take everything after `OK:` as the name
+        write_bytes(&mut stream, format!("OK:{}", name).as_bytes()).await;
+    } else {
+        write_response(&mut stream, format!("OK:{}", name)).await;
+    }
+
+    // Held until the client returns it, disconnects or is closed
+    let mut request_instead = None;
+    let returned = loop {
+        let request = select! {
+            request = protocol::read_line(&mut stream) => request,
+            _ = force_close.cancelled() => {
+                debug!("Closing connection holding {} for shutdown", name);
+                break false;
+            }
+            _ = killed.cancelled() => {
+                debug!("Closing connection holding {} for kill-lease", name);
+                break false;
             }
         };
-        let instant = std::time::Instant::now();
-        // Respont to the client OK:{db_name} or EMPTY:No databases available
-        debug!("Assigned database: {:?}", name);
-        if let Err(e) = stream.write_all(format!("OK:{}", name).as_bytes()).await {
-            debug!("Failed to write to stream: {}", e);
+        match request.map(|line| Request::parse(&line)) {
+            Ok(Some(Request::Return)) => break true,
+            Ok(Some(request)) if legacy => {
+                debug!("Client sent {:?} after a legacy lease of {}", request, name);
+                request_instead = Some(request);
+                break false;
+            }
+            Ok(_) => write_response(&mut stream, "ERROR:Unknown request".to_string()).await,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                debug!("Client disconnected");
+                break false;
+            }
+            Err(e) => {
+                debug!("Client connection failed: {}", e);
+                break false;
+            }
+        }
+    };
+    pool.kills.lock().await.remove(&name);
+    debug!(
+        "Releasing database: {} after {}ms usage",
+        name,
+        instant.elapsed().as_millis()
+    );
+    if let Some(pid) = pid {
+        pool.record_release(pid).await;
+    }
+    pool.return_database(name.clone()).await;
+    if returned {
+        write_response(&mut stream, format!("OK:{}", name)).await;
+    }
+    request_instead.map(|request| (stream, request))
+}
+
+/// Lease a database shared by every connection in `group`, returning it once the last one closes.
+async fn lease_shared(
+    pool: Pool,
+    mut stream: UnixStream,
+    options: AcquireOptions,
+    group: String,
+    force_close: CancellationToken,
+) {
+    let name = {
+        let mut groups = pool.groups.lock().await;
+        let member = groups.entry(group.clone()).or_default();
+        member.members += 1;
+        member.name.clone()
+    };
+    debug!("Assigning database to group {:?}...", group);
+    let name = select! {
+        name = name.get_or_init(|| acquire(&pool, &options)) => Some(name.clone()),
+        _ = force_close.cancelled() => None,
+    };
+    if let Some(name) = name {
+        debug!("Assigned database: {:?} to group {:?}", name, group);
+        write_response(&mut stream, format!("OK:{}", name)).await;
+        let returned = select! {
+            request = protocol::read_line(&mut stream) => {
+                matches!(request, Ok(line) if Request::parse(&line) == Some(Request::Return))
+            }
+            _ = force_close.cancelled() => false,
+        };
+        leave_group(&pool, &group).await;
+        if returned {
+            write_response(&mut stream, format!("OK:{}", name)).await;
+        }
+        return;
+    }
+    leave_group(&pool, &group).await;
+}
+
+async fn leave_group(pool: &Pool, group: &str) {
+    let mut groups = pool.groups.lock().await;
+    let Some(member) = groups.get_mut(group) else {
+        return;
+    };
+    member.members -= 1;
+    if member.members > 0 {
+        return;
+    }
+    let member = groups.remove(group).expect("Group exists");
+    drop(groups);
+    if let Some(name) = member.name.get() {
+        debug!("Releasing database: {} for group {:?}", name, group);
+        pool.return_database(name.clone()).await;
+    }
+}
+
+async fn lease_detached(pool: Pool, mut stream: UnixStream, force_close: CancellationToken) {
+    debug!("Assigning detached database...");
+    let options = AcquireOptions::default();
+    let name = select! {
+        name = acquire(&pool, &options) => name,
+        _ = force_close.cancelled() => return,
+    };
+    let token = lease_token();
+    debug!("Assigned detached database: {:?}", name);
+    pool.leases.lock().await.insert(token.clone(), name.clone());
+    write_response(&mut stream, format!("DETACHED:{}:{}", token, name)).await;
+}
+
+async fn release(pool: Pool, mut stream: UnixStream, token: String) {
+    let Some(name) = pool.leases.lock().await.remove(&token) else {
+        debug!("Unknown lease token: {:?}", token);
+        write_response(&mut stream, "ERROR:Unknown lease token".to_string()).await;
+        return;
+    };
+    debug!("Releasing detached database: {}", name);
+    pool.return_database(name.clone()).await;
+    write_response(&mut stream, format!("OK:{}", name)).await;
+}
+
+/// Force-release `name`, closing the connection holding it or dropping its detached lease.
+async fn kill(pool: Pool, mut stream: UnixStream, name: String) {
+    if let Some(killed) = pool.kills.lock().await.remove(&name) {
+        info!("Killing lease on {}", name);
+        killed.cancel();
+        write_response(&mut stream, format!("OK:{}", name)).await;
+        return;
+    }
+    let mut leases = pool.leases.lock().await;
+    let Some(token) = leases
+        .iter()
+        .find_map(|(token, leased)| (*leased == name).then(|| token.clone()))
+    else {
+        drop(leases);
+        debug!("Database is not leased: {:?}", name);
+        write_response(&mut stream, format!("ERROR:{} is not leased", name)).await;
+        return;
+    };
+    leases.remove(&token);
+    drop(leases);
+    info!("Killing detached lease on {}", name);
+    pool.return_database(name.clone()).await;
+    write_response(&mut stream, format!("OK:{}", name)).await;
+}
+
+/// Report the pool's usage as `OK:<snapshot>`, taken before any reset.
+async fn report_stats(pool: Pool, mut stream: UnixStream, reset: bool) {
+    let snapshot = pool.stats.snapshot();
+    if reset {
+        info!("Resetting usage statistics");
+        pool.stats.reset();
+        stats::reset();
+    }
+    write_response(&mut stream, format!("OK:{}", snapshot)).await;
+}
+
+async fn respond(
+    pool: Pool,
+    connections: &Connections,
+    mut stream: UnixStream,
+    address: SocketAddr,
+) {
+    if let Some(uids) = &pool.allowed_peer_uids {
+        let uid = stream.peer_cred().map(|cred| cred.uid());
+        if !uid.as_ref().is_ok_and(|uid| uids.contains(uid)) {
+            warn!("Refusing connection from {:?}: peer uid {:?}", address, uid);
+            return;
+        }
+    }
+    if let Some(max_connections) = pool.max_connections
+        && pool.stats.connections() >= max_connections
+    {
+        warn!(
+            "Refusing connection from {:?}: {} connections open",
+            address, max_connections
+        );
+        connections.tracker.spawn(async move {
+            let message = format!("ERROR:Too many connections (max {max_connections})");
+            write_response(&mut stream, message).await;
+        });
+        return;
+    }
+
+    let force_close = connections.force_close.clone();
+    pool.stats.open_connection();
+    connections.tracker.spawn(
+        async move {
+            let stats = pool.stats.clone();
+            handle(pool, stream, address, force_close).await;
+            stats.close_connection();
+        }
+        .in_current_span(),
+    );
+}
+
+/// With `PGM_LEGACY_CLIENTS`, how long a new connection has to send a request
+/// before it is treated as a pre-0.4 client
+const LEGACY_CLIENT_GRACE: Duration = Duration::from_millis(100);
+
+async fn handle(
+    pool: Pool,
+    mut stream: UnixStream,
+    address: SocketAddr,
+    force_close: CancellationToken,
+) {
+    debug!("New connection from {:?}", address);
+    // Clients before 0.4 send nothing and expect a database as soon as they connect
+    let sent_request = !pool.legacy_clients
+        || select! {
+            readable = stream.readable() => readable.is_ok(),
+            _ = tokio::time::sleep(LEGACY_CLIENT_GRACE) => false,
+            _ = force_close.cancelled() => return,
+        };
+    if !sent_request {
+        debug!("No request from {:?}, assigning a database", address);
+        let options = AcquireOptions::default();
+        let Some((stream, request)) =
+            lease(pool.clone(), stream, options, force_close.clone(), true).await
+        else {
+            return;
+        };
+        return dispatch(pool, stream, address, Some(request), force_close).await;
+    }
+    let request = select! {
+        request = protocol::read_line(&mut stream) => request,
+        _ = force_close.cancelled() => return,
+    };
+    let request = match request {
+        Ok(line) => Request::parse(&line),
+        Err(e) => {
+            debug!("Failed to read request: {}", e);
+            return;
+        }
+    };
+    dispatch(pool, stream, address, request, force_close).await;
+}
+
+async fn dispatch(
+    pool: Pool,
+    mut stream: UnixStream,
+    address: SocketAddr,
+    request: Option<Request>,
+    force_close: CancellationToken,
+) {
+    match request {
+        Some(Request::Acquire(options)) => {
+            lease(pool, stream, options, force_close, false).await;
+        }
+        Some(Request::Detach) => lease_detached(pool, stream, force_close).await,
+        Some(Request::Release(token)) => release(pool, stream, token).await,
+        Some(Request::Kill(name)) => kill(pool, stream, name).await,
+        Some(Request::Return) => {
+            write_response(&mut stream, "ERROR:No database is leased".to_string()).await
         }
-        stream.flush().await.unwrap();
-
-        let mut buffer = [0; 1024];
-        if let Ok(0) = stream.read(&mut buffer).await {
-            debug!("Client disconnected");
-            debug!(
-                "Releasing database: {} after {}ms usage",
-                name,
-                instant.elapsed().as_millis()
-            );
-            let mut dbs = databases.lock().await;
-            dbs.push_back(name);
-            stats::decrement_usage();
+        Some(Request::Stats { reset }) => report_stats(pool, stream, reset).await,
+        Some(Request::Shutdown) => {
+            info!("Received shutdown request from {:?}", address);
+            write_response(&mut stream, "OK:".to_string()).await;
+            pool.shutdown.cancel();
         }
-    });
+        None => write_response(&mut stream, "ERROR:Unknown request".to_string()).await,
+    }
 }
 
 async fn server(
     listener: UnixListener,
-    databases: Databases,
+    pool: Pool,
+    drain_timeout: Option<Duration>,
     cancellation_token: CancellationToken,
     barrier: Arc<tokio::sync::Barrier>,
 ) {
+    let connections = Connections::default();
     barrier.wait().await;
     loop {
         select! {
@@ -88,50 +1130,286 @@ async fn server(
             accept_result = listener.accept() => {
                 match accept_result {
                     Ok((stream, addr)) => {
-                        let databases = databases.clone();
-                        respond(databases, stream, addr).await;
+                        respond(pool.clone(), &connections, stream, addr).await;
                     }
                     Err(_) => { /* connection failed */ }
                 }
             }
         }
     }
+
+    connections.tracker.close();
+    if let Some(drain_timeout) = drain_timeout {
+        if tokio::time::timeout(drain_timeout, connections.tracker.wait())
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        info!(
+            "Forcing {} connections closed after {}ms drain timeout",
+            connections.tracker.len(),
+            drain_timeout.as_millis()
+        );
+    }
+    // Wind down in-flight connections so their databases are returned before the socket goes
+    connections.force_close.cancel();
+    connections.tracker.wait().await;
 }
 
 pub(crate) fn build_databases(config: Config) -> Databases {
     let mut databases: VecDeque<String> = VecDeque::new();
     for n in 0..config.max_databases {
-        databases.push_back(format!("{}{}", config.prefix, n));
+        let name = match &config.instance_id {
+            Some(instance_id) => format!("{}{}_{}", config.prefix, n, instance_id),
+            None => format!("{}{}", config.prefix, n),
+        };
+        databases.push_back(name);
     }
     Arc::new(Mutex::new(databases))
 }
 
-pub(crate) async fn start_server(
-    path: &Path,
-    config: Config,
-) -> (tokio::task::JoinHandle<()>, CancellationToken) {
-    let cancellation_token = tokio_util::sync::CancellationToken::new();
+/// A `.sql` file from `PGM_MIGRATIONS_DIR`
+#[cfg(any(feature = "postgres", test))]
+#[derive(Debug, PartialEq, Eq)]
+struct Migration {
+    file: String,
+    sql: String,
+}
+
+/// Read the `.sql` files in `dir`, ordered by file name.
+#[cfg(any(feature = "postgres", test))]
+fn read_migrations(dir: &Path) -> Result<Vec<Migration>, String> {
+    let read_error =
+        |e: std::io::Error| format!("Failed to read migrations from {}: {e}", dir.display());
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .map_err(read_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()
+        .map_err(read_error)?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "sql"));
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let file = path
+                .file_name()
+                .expect("Listed file has a name")
+                .to_string_lossy()
+                .into_owned();
+            let sql = std::fs::read_to_string(&path).map_err(read_error)?;
+            Ok(Migration { file, sql })
+        })
+        .collect()
+}
+
+/// Apply `migrations` to `name` in order, stopping at the first one that fails.
+#[cfg(feature = "postgres")]
+async fn migrate_database(name: &str, migrations: &[Migration]) -> Result<(), String> {
+    for migration in migrations {
+        crate::query::execute(name, &migration.sql)
+            .await
+            .map_err(|e| format!("{}: {e}", migration.file))?;
+    }
+    Ok(())
+}
+
+/// Run `check` against every database in `names`, reporting each one that fails.
+#[cfg(any(feature = "postgres", test))]
+async fn validate_databases<F, T, E>(
+    names: &[String],
+    check: impl Fn(String) -> F,
+) -> Result<(), String>
+where
+    F: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let mut failures = Vec::new();
+    for name in names {
+        if let Err(e) = check(name.clone()).await {
+            failures.push(format!("  {name}: {e}"));
+        }
+    }
+    if failures.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "Startup validation failed on {} of {} databases:\n{}",
+        failures.len(),
+        names.len(),
+        failures.join("\n")
+    ))
+}
+
+/// Write `{ "<worker>": "<database>" }` for every database in build order.
+async fn write_assignment_file(path: &Path, databases: &Databases) -> std::io::Result<()> {
+    let entries: Vec<_> = databases
+        .lock()
+        .await
+        .iter()
+        .enumerate()
+        .map(|(n, name)| {
+            format!(
+                "{}:{}",
+                util::json_string(&n.to_string()),
+                util::json_string(name)
+            )
+        })
+        .collect();
+    std::fs::write(path, format!("{{{}}}\n", entries.join(",")))
+}
+
+fn create_socket_dir(dir: &Path) -> Result<(), String> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    if let Some(file) = dir.ancestors().find(|ancestor| ancestor.is_file()) {
+        return Err(format!(
+            "cannot create socket directory: {} is a file",
+            file.display()
+        ));
+    }
+    std::fs::create_dir_all(dir)
+        .map_err(|e| format!("cannot create socket directory {}: {}", dir.display(), e))
+}
+
+/// Whether a server is accepting connections on the socket at `path`.
+pub(crate) async fn server_running(path: &Path) -> bool {
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    is_socket && UnixStream::connect(path).await.is_ok()
+}
+
+/// Bind `path`, replacing a stale socket file left behind by a server that is no longer running.
+async fn bind(path: &Path) -> UnixListener {
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if is_socket {
+        match UnixStream::connect(path).await {
+            Ok(_) => panic!("Socket {} is in use by a running server", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                debug!("Removing stale socket {}", path.display());
+                std::fs::remove_file(path).expect("Failed to remove stale socket file");
+            }
+            Err(_) => { /* let bind report the error */ }
+        }
+    }
+    UnixListener::bind(path).unwrap()
+}
+
+/// With `PGM_EXPECTED_CONCURRENCY` set, warn when the pool looks too small for it.
+fn warn_if_undersized(config: &Config) {
+    if let Some(expected) = config.expected_concurrency
+        && expected > config.max_databases
+    {
+        warn!(
+            "EXPECTED_CONCURRENCY ({}) exceeds DATABASE_COUNT ({}), clients will wait for databases",
+            expected, config.max_databases
+        );
+    }
+}
+
+/// With `PGM_EXPECTED_CONCURRENCY` set, warn at shutdown if every database was leased at once.
+fn warn_if_saturated(config: &Config, stats: &stats::Stats) {
+    if config.expected_concurrency.is_some() && stats.peak_usage() >= config.max_databases {
+        warn!(
+            "All {} databases were leased at peak, DATABASE_COUNT may be too small",
+            config.max_databases
+        );
+    }
+}
+
+pub(crate) async fn start_server(path: &Path, config: Config) -> (ServerHandle, CancellationToken) {
     let barrier = Arc::new(tokio::sync::Barrier::new(2));
-    let databases = build_databases(config);
+    warn_if_undersized(&config);
+    let name = config.prefix.clone();
+    let drain_timeout = config.drain_timeout;
+    let assignment_file = config.assignment_file.clone();
+    let pool = Pool::new(config.clone());
+    let cancellation_token = pool.shutdown.clone();
+    #[cfg(feature = "postgres")]
+    // Only databases created here are dropped at shutdown, ones made beforehand are kept
+    let created = match &config.create_db_sql {
+        Some(sql) => {
+            let names: Vec<_> = pool.databases.lock().await.iter().cloned().collect();
+            crate::query::create_databases(&names, sql)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to create databases: {e}"))
+        }
+        None => Vec::new(),
+    };
+    #[cfg(feature = "postgres")]
+    if !pool.migrations.is_empty() {
+        let names: Vec<_> = pool.databases.lock().await.iter().cloned().collect();
+        for name in &names {
+            migrate_database(name, &pool.migrations)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to migrate database {name}: {e}"));
+        }
+        debug!(
+            "Applied {} migrations to {} databases",
+            pool.migrations.len(),
+            names.len()
+        );
+    }
+    #[cfg(feature = "postgres")]
+    if let Some(sql) = &config.startup_validate_sql {
+        let names: Vec<_> = pool.databases.lock().await.iter().cloned().collect();
+        validate_databases(
+            &names,
+            |name| async move { crate::query::run(&name, sql).await },
+        )
+        .await
+        .unwrap_or_else(|report| panic!("{report}"));
+        debug!("Validated {} databases", names.len());
+    }
+    if let Some(assignment_file) = assignment_file {
+        write_assignment_file(&assignment_file, &pool.databases)
+            .await
+            .expect("Failed to write assignment file");
+        debug!("Wrote assignments to {}", assignment_file.display());
+    }
 
     if path.is_dir() {
         panic!("Socket path cannot be a directory");
     }
     let parent_dir = path.parent().expect("Socket needs to be in a directory");
-    if !parent_dir.exists() {
-        std::fs::create_dir_all(parent_dir).unwrap();
+    create_socket_dir(parent_dir).unwrap_or_else(|err| panic!("{err}"));
+    if let Some(lock_dir) = &config.lock_dir {
+        std::fs::create_dir_all(lock_dir).expect("Failed to create lock directory");
     }
     let server = {
         let path = path.to_path_buf();
         let cancellation_token = cancellation_token.clone();
         let barrier = barrier.clone();
-        let listener = UnixListener::bind(path.clone()).unwrap();
-        tokio::spawn(async move {
-            let result = server(listener, databases, cancellation_token, barrier).await;
-            info!("Shutting down server...");
-            std::fs::remove_file(&path).expect("Failed to remove socket file");
-            result
-        })
+        let listener = bind(&path).await;
+        tokio::spawn(
+            async move {
+                let stats = pool.stats.clone();
+                let lock_dir_watcher = config.lock_dir.clone().map(|lock_dir| {
+                    tokio::spawn(
+                        watch_lock_dir(pool.clone(), lock_dir, cancellation_token.clone())
+                            .in_current_span(),
+                    )
+                });
+                server(listener, pool, drain_timeout, cancellation_token, barrier).await;
+                if let Some(lock_dir_watcher) = lock_dir_watcher {
+                    lock_dir_watcher
+                        .await
+                        .expect("Lock directory watcher failed");
+                }
+                info!("Shutting down server...");
+                stats.log_usage(&name);
+                warn_if_saturated(&config, &stats);
+                #[cfg(feature = "postgres")]
+                if !created.is_empty()
+                    && let Err(e) = crate::query::drop_databases(&created).await
+                {
+                    error!("Failed to drop databases: {}", e);
+                }
+                std::fs::remove_file(&path).expect("Failed to remove socket file");
+                stats
+            }
+            .in_current_span(),
+        )
     };
     barrier.wait().await;
     debug!("Listening on {}", path.display());
@@ -142,6 +1420,7 @@ pub(crate) async fn start_server(
 mod tests {
     use super::*;
     use crate::test_helpers;
+    use tokio::io::AsyncReadExt as _;
 
     #[tokio::test]
     async fn test_build_databases() {
@@ -152,6 +1431,759 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[tokio::test]
+    async fn test_instance_suffix_keeps_managers_apart() {
+        let config_a = Config::new(4, "test_db_".to_string()).with_instance_suffix();
+        let config_b = Config::new(4, "test_db_".to_string()).with_instance_suffix();
+        let instance_id = config_a.instance_id.clone().unwrap();
+
+        let names_a = build_databases(config_a).lock().await.clone();
+        let names_b = build_databases(config_b).lock().await.clone();
+        assert_eq!(names_a[0], format!("test_db_0_{instance_id}"));
+        assert!(names_a.iter().all(|name| !names_b.contains(name)));
+    }
+
+    #[test]
+    fn test_instance_suffix_requires_create_db_sql() {
+        let config = Config::new(1, "test_db_".to_string()).with_instance_suffix();
+        let err = config.clone().validate_instance_suffix().err().unwrap();
+        assert!(
+            err.starts_with("INSTANCE_SUFFIX requires CREATE_DB_SQL"),
+            "{err}"
+        );
+
+        let config = config.with_create_db_sql("CREATE DATABASE {name}".to_string());
+        assert!(config.validate_instance_suffix().is_ok());
+        let config = Config::new(1, "test_db_".to_string());
+        assert!(config.validate_instance_suffix().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_reserve_free_is_kept_for_priority_requests() {
+        let pool = Pool::new(Config::new(2, "test_db_".to_string()).with_reserve_free(1));
+
+        let normal = AcquireOptions::default();
+        let priority = AcquireOptions {
+            priority: true,
+            ..Default::default()
+        };
+        assert_eq!(acquire(&pool, &normal).await, "test_db_0");
+        let blocked =
+            tokio::time::timeout(Duration::from_millis(50), acquire(&pool, &normal)).await;
+        assert!(blocked.is_err());
+        assert_eq!(acquire(&pool, &priority).await, "test_db_1");
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_hands_out_in_build_order() {
+        let pool = Pool::new(Config::new(4, "test_db_".to_string()).with_deterministic());
+        let options = AcquireOptions::default();
+        let expected: Vec<_> = (0..4).map(|n| format!("test_db_{n}")).collect();
+
+        let mut names = Vec::new();
+        for _ in 0..4 {
+            names.push(acquire(&pool, &options).await);
+        }
+        assert_eq!(names, expected);
+
+        // Returned out of order, they still come back by build index
+        for name in [2, 0, 3, 1].map(|n| names[n].clone()) {
+            pool.return_database(name).await;
+        }
+        let mut names = Vec::new();
+        for _ in 0..4 {
+            names.push(acquire(&pool, &options).await);
+        }
+        assert_eq!(names, expected);
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_blocked_waiters_hold_back_no_one() {
+        let pool = Pool::new(
+            Config::new(3, "test_db_".to_string())
+                .with_reserve_free(1)
+                .with_deterministic(),
+        );
+        let timeout = Duration::from_millis(500);
+        assert_eq!(
+            acquire(&pool, &AcquireOptions::default()).await,
+            "test_db_0"
+        );
+        assert_eq!(
+            acquire(&pool, &AcquireOptions::default()).await,
+            "test_db_1"
+        );
+
+        // Kept out of the reserve
+        let normal = {
+            let pool = pool.clone();
+            tokio::spawn(async move { acquire(&pool, &AcquireOptions::default()).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let priority = AcquireOptions {
+            priority: true,
+            ..Default::default()
+        };
+        let name = tokio::time::timeout(timeout, acquire(&pool, &priority))
+            .await
+            .expect("Priority acquire waited behind a normal one");
+        assert_eq!(name, "test_db_2");
+        pool.return_database(name).await;
+        normal.abort();
+
+        // Waiting for a database someone else holds
+        let named = {
+            let pool = pool.clone();
+            let options = AcquireOptions {
+                name: Some("test_db_0".to_string()),
+                ..Default::default()
+            };
+            tokio::spawn(async move { acquire(&pool, &options).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let name = tokio::time::timeout(timeout, acquire(&pool, &priority))
+            .await
+            .expect("Acquire waited behind a named one");
+        assert_eq!(name, "test_db_2");
+        named.abort();
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_serves_waiters_in_turn() {
+        let pool = Pool::new(Config::new(1, "test_db_".to_string()).with_deterministic());
+        let name = acquire(&pool, &AcquireOptions::default()).await;
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        for n in 0..3 {
+            let pool = pool.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let name = acquire(&pool, &AcquireOptions::default()).await;
+                sender.send(n).unwrap();
+                pool.return_database(name).await;
+            });
+            // Let each waiter queue up before the next
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        pool.return_database(name).await;
+        let mut order = Vec::new();
+        for _ in 0..3 {
+            order.push(receiver.recv().await.unwrap());
+        }
+        assert_eq!(order, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_wait_poll() {
+        let pool = Pool::new(
+            Config::new(1, "test_db_".to_string()).with_wait_poll(Duration::from_millis(30)),
+        );
+        let options = AcquireOptions::default();
+        let name = acquire(&pool, &options).await;
+
+        let waiting = {
+            let pool = pool.clone();
+            tokio::spawn(async move { acquire(&pool, &AcquireOptions::default()).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        pool.databases.lock().await.push_back(name);
+        waiting.await.unwrap();
+
+        let total_wait = pool.stats.total_wait();
+        assert!(total_wait >= 90, "waited {total_wait}ms");
+        assert_eq!(total_wait % 30, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reacquire_cooldown() {
+        let path = test_helpers::temp_path();
+        let cooldown = Duration::from_millis(300);
+        let config = Config::new(1, "test_db_".to_string()).with_reacquire_cooldown(cooldown);
+        let (server, cancellation_token) = start_server(&path, config).await;
+
+        let (held, _) = test_helpers::send_request(&path, "ACQUIRE:").await;
+        let waiting = {
+            let path = path.clone();
+            tokio::spawn(async move { test_helpers::send_request(&path, "ACQUIRE:").await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Release and immediately ask again from the same process
+        drop(held);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let started = Instant::now();
+        let reacquiring = {
+            let path = path.clone();
+            tokio::spawn(async move { test_helpers::send_request(&path, "ACQUIRE:").await })
+        };
+
+        // The client that was already waiting gets the database first
+        let (waiter, response) = waiting.await.unwrap();
+        assert_eq!(response, "OK:test_db_0");
+        assert!(!reacquiring.is_finished());
+        drop(waiter);
+
+        let (_reacquired, response) = reacquiring.await.unwrap();
+        assert_eq!(response, "OK:test_db_0");
+        assert!(started.elapsed() >= cooldown - Duration::from_millis(20));
+
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[test]
+    fn test_validate_create_db_sql() {
+        let config = Config::new(1, "test_db_".to_string()).with_create_db_sql("CREATE".into());
+        let err = config.validate().err().unwrap();
+        if cfg!(feature = "postgres") {
+            assert_eq!(err, "CREATE_DB_SQL must contain a {name} placeholder");
+        } else {
+            assert_eq!(err, "CREATE_DB_SQL requires the postgres feature");
+        }
+    }
+
+    #[test]
+    fn test_read_migrations() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("2_posts.sql"), "CREATE TABLE posts ()").unwrap();
+        std::fs::write(dir.path().join("1_users.sql"), "CREATE TABLE users ()").unwrap();
+        std::fs::write(dir.path().join("README.md"), "Not a migration").unwrap();
+
+        let migrations = read_migrations(dir.path()).unwrap();
+        assert_eq!(
+            migrations,
+            [
+                Migration {
+                    file: "1_users.sql".to_string(),
+                    sql: "CREATE TABLE users ()".to_string(),
+                },
+                Migration {
+                    file: "2_posts.sql".to_string(),
+                    sql: "CREATE TABLE posts ()".to_string(),
+                },
+            ]
+        );
+
+        let err = read_migrations(&dir.path().join("missing")).unwrap_err();
+        assert!(err.starts_with("Failed to read migrations from"), "{err}");
+    }
+
+    /// Needs a reachable postgres where `PGUSER` may create databases
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[ignore = "requires a running postgres"]
+    async fn test_shutdown_keeps_databases_made_beforehand() {
+        let prefix = format!("pgm_test_{:x}_", util::random_u64());
+        let existing = vec![format!("{prefix}0")];
+        crate::query::create_databases(&existing, "CREATE DATABASE {name}")
+            .await
+            .unwrap();
+        let config =
+            Config::new(2, prefix.clone()).with_create_db_sql("CREATE DATABASE {name}".to_string());
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) = test_helpers::temp_server(&path, Some(config)).await;
+        cancellation_token.cancel();
+        server.await.unwrap();
+
+        let names = vec![format!("{prefix}0"), format!("{prefix}1")];
+        let remaining = crate::query::create_databases(&names, "CREATE DATABASE {name}")
+            .await
+            .unwrap();
+        assert_eq!(
+            remaining,
+            [format!("{prefix}1")],
+            "Only the server's own database was dropped"
+        );
+        crate::query::drop_databases(&names).await.unwrap();
+    }
+
+    /// Needs a reachable postgres where `PGUSER` may create databases
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[ignore = "requires a running postgres"]
+    async fn test_migrations_are_applied_to_every_database() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("1_users.sql"),
+            "CREATE TABLE users (id serial)",
+        )
+        .unwrap();
+        let prefix = format!("pgm_test_{:x}_", util::random_u64());
+        let config = Config::new(2, prefix.clone())
+            .with_create_db_sql("CREATE DATABASE {name}".to_string())
+            .with_migrations_dir(dir.path().to_path_buf());
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) = test_helpers::temp_server(&path, Some(config)).await;
+
+        for n in 0..2 {
+            let tables = crate::query::run(
+                &format!("{prefix}{n}"),
+                "SELECT count(*) FROM pg_tables WHERE tablename = 'users'",
+            )
+            .await
+            .unwrap();
+            assert!(
+                tables
+                    .format(crate::query::OutputFormat::Csv)
+                    .ends_with("\n1\n")
+            );
+        }
+        cancellation_token.cancel();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_validate_databases_reports_failures() {
+        let names: Vec<_> = (0..3).map(|n| format!("test_db_{n}")).collect();
+        let report = validate_databases(&names, |name| async move {
+            match name.as_str() {
+                "test_db_1" => Err("extension \"postgis\" is not available"),
+                _ => Ok(()),
+            }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            report,
+            "Startup validation failed on 1 of 3 databases:\n  test_db_1: extension \"postgis\" is not available"
+        );
+        assert!(
+            validate_databases(&names, |_| async { Ok::<_, String>(()) })
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_parse_reset_strategy() {
+        assert_eq!("none".parse(), Ok(ResetStrategy::None));
+        assert_eq!("truncate".parse(), Ok(ResetStrategy::Truncate));
+        assert_eq!("drop-schema".parse(), Ok(ResetStrategy::DropSchema));
+        assert!("drop".parse::<ResetStrategy>().is_err());
+
+        let config = Config::new(1, "test_db_".to_string()).with_reset(ResetStrategy::Truncate);
+        assert_eq!(
+            config.validate().is_ok(),
+            cfg!(feature = "postgres"),
+            "DATABASE_RESET needs a postgres connection"
+        );
+    }
+
+    /// Needs a reachable postgres where `PGUSER` may create databases
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[ignore = "requires a running postgres"]
+    async fn test_reset_before_returning() {
+        async fn lease(pool: &Pool) -> String {
+            acquire(pool, &AcquireOptions::default()).await
+        }
+
+        let prefix = format!("pgm_test_{:x}_", util::random_u64());
+        let names = vec![format!("{prefix}0")];
+        crate::query::create_databases(&names, "CREATE DATABASE {name}")
+            .await
+            .unwrap();
+        for reset in [ResetStrategy::DropSchema, ResetStrategy::Truncate] {
+            let pool = Pool::new(Config::new(1, prefix.clone()).with_reset(reset));
+            let name = lease(&pool).await;
+            crate::query::execute(
+                &name,
+                "DROP TABLE IF EXISTS users; CREATE TABLE users (id serial); INSERT INTO users DEFAULT VALUES;",
+            )
+            .await
+            .unwrap();
+            pool.return_database(name).await;
+
+            let name = lease(&pool).await;
+            let tables = crate::query::run(
+                &name,
+                "SELECT count(*) FROM pg_tables WHERE tablename = 'users'",
+            )
+            .await
+            .unwrap();
+            let rows = crate::query::run(&name, "SELECT count(*) FROM users").await;
+            match reset {
+                ResetStrategy::DropSchema => {
+                    assert!(
+                        tables
+                            .format(crate::query::OutputFormat::Csv)
+                            .ends_with("\n0\n")
+                    );
+                    assert!(rows.is_err());
+                }
+                _ => {
+                    let rows = rows.unwrap().format(crate::query::OutputFormat::Csv);
+                    assert!(rows.ends_with("\n0\n"), "{rows}");
+                }
+            }
+        }
+        crate::query::drop_databases(&names).await.unwrap();
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    #[ignore = "requires a running postgres"]
+    async fn test_failed_reset_keeps_database_out_of_pool() {
+        // Never created, so the reset can't connect to it
+        let prefix = format!("pgm_test_{:x}_", util::random_u64());
+        let pool = Pool::new(Config::new(1, prefix).with_reset(ResetStrategy::Truncate));
+        let name = acquire(&pool, &AcquireOptions::default()).await;
+        pool.return_database(name).await;
+
+        assert!(pool.databases.lock().await.is_empty());
+        assert_eq!(pool.stats.usage(), 0);
+        assert!(
+            try_acquire(&pool, &AcquireOptions::default())
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_legacy_client_gets_a_database_on_connect() {
+        let path = test_helpers::temp_path();
+        let config = Config::new(1, "test_db_".to_string()).with_legacy_clients();
+        let (server, cancellation_token) = test_helpers::temp_server(&path, Some(config)).await;
+
+        for _ in 0..2 {
+            let mut stream = test_helpers::temp_client(&path).await;
+            let mut buffer = [0; 1024];
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..read], b"OK:test_db_0");
+            drop(stream);
+            // Closing the connection releases it for the next one
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // A request sent after the grace period returns the database and is answered
+        let mut stream = test_helpers::temp_client(&path).await;
+        let mut buffer = [0; 1024];
+        let read = stream.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..read], b"OK:test_db_0");
+        stream.write_all(b"STATS:\n").await.unwrap();
+        let read = stream.read(&mut buffer).await.unwrap();
+        let response = String::from_utf8_lossy(&buffer[..read]);
+        assert!(response.starts_with("OK:usage=0;"), "{response}");
+
+        cancellation_token.cancel();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_waits_without_legacy_clients() {
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) = test_helpers::temp_server(&path, None).await;
+
+        let mut stream = test_helpers::temp_client(&path).await;
+        let mut buffer = [0; 1024];
+        let read = tokio::time::timeout(Duration::from_millis(300), stream.read(&mut buffer)).await;
+        assert!(read.is_err(), "Leased a database without a request");
+        // However slow, a request is still answered as sent
+        let (_, response) = test_helpers::send_request(&path, "ACQUIRE:nowait").await;
+        assert_eq!(response, "OK:test_db_0");
+
+        drop(stream);
+        cancellation_token.cancel();
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_warn_if_undersized() {
+        let config = Config::new(2, "test_db_".to_string());
+        assert_eq!(
+            test_helpers::capture_logs(|| warn_if_undersized(&config)),
+            ""
+        );
+        let config = config.with_expected_concurrency(4);
+        let logs = test_helpers::capture_logs(|| warn_if_undersized(&config));
+        assert!(logs.contains("EXPECTED_CONCURRENCY (4) exceeds DATABASE_COUNT (2)"));
+    }
+
+    #[test]
+    fn test_warn_if_saturated() {
+        let config = Config::new(2, "test_db_".to_string()).with_expected_concurrency(2);
+        let stats = stats::Stats::default();
+        stats.increment_usage();
+        assert_eq!(
+            test_helpers::capture_logs(|| warn_if_saturated(&config, &stats)),
+            ""
+        );
+        stats.increment_usage();
+        let logs = test_helpers::capture_logs(|| warn_if_saturated(&config, &stats));
+        assert!(logs.contains("All 2 databases were leased at peak"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_acquires_never_share_a_database() {
+        let pool = Pool::new(Config::new(4, "test_db_".to_string()));
+        let valid: HashSet<String> = pool.databases.lock().await.iter().cloned().collect();
+        let held = Arc::new(std::sync::Mutex::new(HashSet::new()));
+
+        let waiters: Vec<_> = (0..32)
+            .map(|_| {
+                let pool = pool.clone();
+                let valid = valid.clone();
+                let held = held.clone();
+                tokio::spawn(async move {
+                    for _ in 0..5 {
+                        let name = acquire(&pool, &AcquireOptions::default()).await;
+                        assert!(valid.contains(&name), "Got unknown database {name}");
+                        assert!(
+                            held.lock().unwrap().insert(name.clone()),
+                            "{name} was handed out twice"
+                        );
+                        tokio::task::yield_now().await;
+                        held.lock().unwrap().remove(&name);
+                        pool.databases.lock().await.push_back(name);
+                        pool.stats.decrement_usage();
+                    }
+                })
+            })
+            .collect();
+        for waiter in waiters {
+            tokio::time::timeout(Duration::from_secs(10), waiter)
+                .await
+                .expect("Waiter never got a database")
+                .expect("Waiter failed");
+        }
+        assert_eq!(pool.databases.lock().await.len(), 4);
+        assert_eq!(pool.stats.usage(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_by_capability() {
+        let capabilities = parse_capabilities(r#"{"1": ["postgis", "hstore"]}"#).unwrap();
+        let pool =
+            Pool::new(Config::new(2, "test_db_".to_string()).with_capabilities(capabilities));
+        let postgis = AcquireOptions {
+            capabilities: ["postgis".to_string()].into(),
+            ..Default::default()
+        };
+        let timescale = AcquireOptions {
+            capabilities: ["timescale".to_string()].into(),
+            ..Default::default()
+        };
+
+        assert!(pool.can_satisfy(&postgis));
+        assert!(!pool.can_satisfy(&timescale));
+        assert_eq!(acquire(&pool, &postgis).await, "test_db_1");
+        let blocked =
+            tokio::time::timeout(Duration::from_millis(50), acquire(&pool, &postgis)).await;
+        assert!(blocked.is_err());
+        assert_eq!(
+            acquire(&pool, &AcquireOptions::default()).await,
+            "test_db_0"
+        );
+    }
+
+    #[test]
+    fn test_read_prefix_file() {
+        let path = test_helpers::temp_path();
+        std::fs::write(&path, "secret_prefix_\n").unwrap();
+        assert_eq!(read_prefix_file(&path).unwrap(), "secret_prefix_");
+
+        std::fs::write(&path, "\n").unwrap();
+        assert!(read_prefix_file(&path).unwrap_err().contains("is empty"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_validate_rejects_zero_count() {
+        let err = Config::new(0, "test_db_".to_string())
+            .validate()
+            .err()
+            .expect("Zero databases should be rejected");
+        assert!(err.contains("DATABASE_COUNT must be at least 1"));
+        assert!(Config::new(1, "test_db_".to_string()).validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pool_stats_are_independent() {
+        let pool_a = Pool::new(Config::new(2, "pool_a_".to_string()));
+        let pool_b = Pool::new(Config::new(2, "pool_b_".to_string()));
+
+        let name = acquire(&pool_a, &AcquireOptions::default()).await;
+        acquire(&pool_a, &AcquireOptions::default()).await;
+        assert_eq!(name, "pool_a_0");
+        assert_eq!(pool_a.stats.usage(), 2);
+        assert_eq!(pool_a.stats.peak_usage(), 2);
+        assert_eq!(pool_b.stats.usage(), 0);
+        assert_eq!(pool_b.stats.peak_usage(), 0);
+
+        pool_a.stats.decrement_usage();
+        assert_eq!(pool_a.stats.usage(), 1);
+        assert_eq!(pool_a.stats.peak_usage(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout_forces_leases_closed() {
+        let path = test_helpers::temp_path();
+        let config =
+            Config::new(1, "test_db_".to_string()).with_drain_timeout(Duration::from_millis(50));
+        let (server, cancellation_token) = start_server(&path, config).await;
+
+        let (mut stream, response) = test_helpers::send_request(&path, "ACQUIRE:").await;
+        assert_eq!(response, "OK:test_db_0");
+        let mut buffer = [0; 1024];
+
+        cancellation_token.cancel();
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("Shutdown did not finish after the drain timeout")
+            .expect("Server task failed");
+        // The lease outlived the drain timeout so the server closed it
+        assert_eq!(stream.read(&mut buffer).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let path = test_helpers::temp_path();
+        let config = Config::new(2, "test_db_".to_string())
+            .with_max_connections(1)
+            .with_drain_timeout(Duration::from_secs(1));
+        let (server, cancellation_token) = start_server(&path, config).await;
+
+        let (held, response) = test_helpers::send_request(&path, "ACQUIRE:").await;
+        assert_eq!(response, "OK:test_db_0");
+        let (_, response) = test_helpers::send_request(&path, "ACQUIRE:").await;
+        assert_eq!(response, "ERROR:Too many connections (max 1)");
+
+        // Closing the first connection makes room for another
+        drop(held);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (stream, response) = test_helpers::send_request(&path, "ACQUIRE:").await;
+        assert!(response.starts_with("OK:"));
+        drop(stream);
+
+        cancellation_token.cancel();
+        let stats = server.await.expect("Server task failed");
+        assert_eq!(stats.connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_kill_lease() {
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) =
+            start_server(&path, Config::new(2, "test_db_".to_string())).await;
+        let (mut killed, response) = test_helpers::send_request(&path, "ACQUIRE:").await;
+        assert_eq!(response, "OK:test_db_0");
+        let (mut kept, response) = test_helpers::send_request(&path, "ACQUIRE:").await;
+        assert_eq!(response, "OK:test_db_1");
+
+        let (_, response) = test_helpers::send_request(&path, "KILL:test_db_0").await;
+        assert_eq!(response, "OK:test_db_0");
+        let mut buffer = [0; 1024];
+        assert_eq!(killed.read(&mut buffer).await.unwrap(), 0);
+        let (_, response) = test_helpers::send_request(&path, "ACQUIRE:").await;
+        assert_eq!(response, "OK:test_db_0");
+        let pending = tokio::time::timeout(Duration::from_millis(50), kept.read(&mut buffer)).await;
+        assert!(pending.is_err());
+
+        let (_, response) = test_helpers::send_request(&path, "KILL:test_db_9").await;
+        assert_eq!(response, "ERROR:test_db_9 is not leased");
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_returns_leased_databases() {
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) =
+            start_server(&path, Config::new(2, "test_db_".to_string())).await;
+
+        let (mut leased, response) = test_helpers::send_request(&path, "ACQUIRE:").await;
+        assert_eq!(response, "OK:test_db_0");
+        let mut buffer = [0; 1024];
+        // Connected but not yet sent a request
+        let mut idle = test_helpers::temp_client(&path).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        cancellation_token.cancel();
+        let stats = tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("Shutdown waited on open connections")
+            .expect("Server task failed");
+        assert_eq!(stats.usage(), 0);
+        assert_eq!(leased.read(&mut buffer).await.unwrap(), 0);
+        assert_eq!(idle.read(&mut buffer).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_restrict_peer_uid() {
+        async fn connection_closed(path: &Path) -> bool {
+            let mut stream = test_helpers::temp_client(path).await;
+            stream.write_all(b"ACQUIRE:\n").await.ok();
+            let mut buffer = [0; 1024];
+            !matches!(stream.read(&mut buffer).await, Ok(read) if read > 0)
+        }
+
+        let uid = util::current_uid();
+        assert_eq!(parse_peer_uids("true"), Ok(Some([uid].into())));
+        assert_eq!(parse_peer_uids("false"), Ok(None));
+        assert!(parse_peer_uids("root").is_err());
+
+        let path = test_helpers::temp_path();
+        let config = Config::new(1, "test_db_".to_string()).with_allowed_peer_uids([uid].into());
+        let (server, cancellation_token) = start_server(&path, config).await;
+        assert!(!connection_closed(&path).await);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+
+        let config =
+            Config::new(1, "test_db_".to_string()).with_allowed_peer_uids([uid + 1].into());
+        let (server, cancellation_token) = start_server(&path, config).await;
+        assert!(connection_closed(&path).await);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_assignment_file() {
+        let path = test_helpers::temp_path();
+        let assignment_file = test_helpers::temp_path();
+        let config =
+            Config::new(2, "test_db_".to_string()).with_assignment_file(assignment_file.clone());
+        let (server, cancellation_token) = start_server(&path, config).await;
+
+        let contents = std::fs::read_to_string(&assignment_file).unwrap();
+        assert_eq!(contents, "{\"0\":\"test_db_0\",\"1\":\"test_db_1\"}\n");
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+        std::fs::remove_file(assignment_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restart_on_same_path() {
+        let path = test_helpers::temp_path();
+        for _ in 0..10 {
+            let config = Config::new(1, "test_db_".to_string());
+            let (server, cancellation_token) = start_server(&path, config).await;
+            cancellation_token.cancel();
+            server.await.expect("Server task failed");
+        }
+
+        // A socket file left behind without a server
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let config = Config::new(1, "test_db_".to_string());
+        let (server, cancellation_token) = start_server(&path, config).await;
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[test]
+    fn test_socket_dir_under_a_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let dir = file.path().join("sockets");
+        let err = create_socket_dir(&dir).unwrap_err();
+        assert_eq!(
+            err,
+            format!(
+                "cannot create socket directory: {} is a file",
+                file.path().display()
+            )
+        );
+    }
+
     #[tokio::test]
     async fn smoke_test_start_server() {
         let path = test_helpers::temp_path();
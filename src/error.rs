@@ -0,0 +1,58 @@
+use std::{fmt::Display, time::Duration};
+
+/// Why a database could not be leased, returned by [`crate::try_get_database`].
+#[derive(Debug)]
+pub enum PgmError {
+    /// The manager socket could not be connected to
+    Connect(std::io::Error),
+    /// Talking to the manager failed after connecting
+    Io(std::io::Error),
+    /// The manager closed the connection before assigning a database
+    Closed,
+    /// The manager's response could not be understood
+    InvalidResponse(String),
+    /// No database can be assigned to the request
+    Empty(String),
+    /// The manager refused the request, e.g. because it has too many connections
+    Refused(String),
+    /// No database was assigned within this long
+    Timeout(Duration),
+}
+
+impl From<std::io::Error> for PgmError {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => Self::Closed,
+            _ => Self::Io(error),
+        }
+    }
+}
+
+impl Display for PgmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connect(e) => write!(f, "Failed to connect to test manager socket: {e}"),
+            Self::Io(e) => write!(f, "Failed to talk to test manager: {e}"),
+            Self::Closed => f.write_str("Test manager socket closed unexpectedly"),
+            Self::InvalidResponse(response) => {
+                write!(f, "Unexpected response from test manager: {response}")
+            }
+            Self::Empty(message) => write!(f, "No databases available: {message}"),
+            Self::Refused(message) => write!(f, "Test manager refused the request: {message}"),
+            Self::Timeout(timeout) => write!(
+                f,
+                "Timed out after {}ms waiting for test manager",
+                timeout.as_millis()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PgmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connect(e) | Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -1,17 +1,115 @@
 pub mod commands;
 mod core;
-mod stats;
+mod error;
+pub mod logging;
+mod protocol;
+#[cfg(feature = "postgres")]
+mod query;
+pub mod stats;
 mod util;
 
-use std::{fmt::Display, ops::Deref};
+use std::{
+    fmt::Display,
+    io::ErrorKind,
+    ops::Deref,
+    os::{fd::AsFd, unix::fs::MetadataExt as _},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
-use tokio::{io::AsyncReadExt, net::UnixStream};
+use tokio::{io::AsyncWriteExt, net::UnixStream};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, info, warn};
+
+use crate::protocol::{AcquireOptions, Request};
+
+pub use crate::error::PgmError;
 
 pub const DEFAULT_SOCKET_PATH: &str = "tmp/pgmanager.sock";
 
+tokio::task_local! {
+    static CURRENT_DATABASE: String;
+}
+
 pub struct DatabaseGuard {
     pub name: String,
-    _stream: UnixStream,
+    stream: UnixStream,
+    /// Env file written by [`get_database_to_file`], removed on drop
+    env_file: Option<PathBuf>,
+    /// Stops the watchdog started by [`get_database_with_max_hold`] on drop
+    _watchdog: Option<DropGuard>,
+}
+
+impl DatabaseGuard {
+    /// Return the database to the pool straight away, e.g. as soon as a
+    /// test's transaction finishes, rather than when the connection closes.
+    /// Resolves once the server has taken the database back.
+    pub async fn release(mut self) {
+        let request = Request::Return.to_string();
+        if let Err(e) = self.stream.write_all(request.as_bytes()).await {
+            // The connection is gone, which released the database already
+            debug!("Failed to return test database {}: {}", self.name, e);
+            return;
+        }
+        match try_read_response(&mut self.stream, client_read_timeout()).await {
+            Ok(response) if response.starts_with("OK:") => {}
+            Ok(response) => warn!("Unexpected response from test manager: {}", response),
+            Err(e) => debug!("Failed to return test database {}: {}", self.name, e),
+        }
+    }
+
+    /// Atomically write `PGDATABASE=<name>` to `path`, to be removed when the guard drops.
+    fn write_env_file(&mut self, path: &Path) -> std::io::Result<()> {
+        let file_name = path.file_name().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Path has no file name")
+        })?;
+        let temp_path = path.with_file_name(format!(
+            ".{}.{:x}.tmp",
+            file_name.to_string_lossy(),
+            util::random_u64()
+        ));
+        std::fs::write(&temp_path, format!("PGDATABASE={}\n", self.name))?;
+        std::fs::rename(&temp_path, path)?;
+        self.env_file = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Shut the connection down, releasing the database, if the guard is
+    /// still held after `max_hold`.
+    fn spawn_watchdog(&mut self, max_hold: Duration) -> std::io::Result<()> {
+        let stream =
+            std::os::unix::net::UnixStream::from(self.stream.as_fd().try_clone_to_owned()?);
+        let cancellation_token = CancellationToken::new();
+        let dropped = cancellation_token.clone();
+        let name = self.name.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = dropped.cancelled() => {}
+                _ = tokio::time::sleep(max_hold) => {
+                    warn!(
+                        "Releasing test database: {} still held after {}ms",
+                        name,
+                        max_hold.as_millis()
+                    );
+                    stream.shutdown(std::net::Shutdown::Both).ok();
+                }
+            }
+        });
+        self._watchdog = Some(cancellation_token.drop_guard());
+        Ok(())
+    }
+}
+
+impl Drop for DatabaseGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.env_file.take()
+            && let Err(e) = std::fs::remove_file(&path)
+        {
+            debug!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
 }
 
 impl Deref for DatabaseGuard {
@@ -34,40 +132,687 @@ impl From<&DatabaseGuard> for String {
     }
 }
 
+/// A [`DatabaseGuard`] that logs how long the lease was held when dropped.
+pub struct TimedGuard {
+    guard: DatabaseGuard,
+    acquired: Instant,
+}
+
+impl TimedGuard {
+    /// How long the database has been leased so far
+    pub fn held(&self) -> Duration {
+        self.acquired.elapsed()
+    }
+}
+
+impl From<DatabaseGuard> for TimedGuard {
+    fn from(guard: DatabaseGuard) -> Self {
+        Self {
+            guard,
+            acquired: Instant::now(),
+        }
+    }
+}
+
+impl Deref for TimedGuard {
+    type Target = DatabaseGuard;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl Display for TimedGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.guard.fmt(f)
+    }
+}
+
+impl Drop for TimedGuard {
+    fn drop(&mut self) {
+        debug!(
+            "Releasing test database: {} after {}ms",
+            self.guard,
+            self.held().as_millis()
+        );
+    }
+}
+
+/// A database leased to a lock file by a server watching `PGM_LOCK_DIR`.
+///
+/// The lock file is removed on drop, which releases the database.
+pub struct LockFileGuard {
+    pub name: String,
+    lock_file: PathBuf,
+}
+
+impl Drop for LockFileGuard {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.lock_file) {
+            debug!("Failed to remove {}: {}", self.lock_file.display(), e);
+        }
+    }
+}
+
+impl Deref for LockFileGuard {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        self.name.as_str()
+    }
+}
+
+impl Display for LockFileGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self)
+    }
+}
+
+/// Databases yielded by [`database_stream`] as they become available.
+pub struct DatabaseStream {
+    receiver: tokio::sync::mpsc::Receiver<Result<DatabaseGuard, PgmError>>,
+}
+
+impl futures_core::Stream for DatabaseStream {
+    type Item = Result<DatabaseGuard, PgmError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// A database leased independently of the connection that acquired it.
+///
+/// The database stays leased until it is passed to [`release`].
+pub struct DetachedLease {
+    pub name: String,
+    pub token: String,
+}
+
+impl Display for DetachedLease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+fn socket_path() -> String {
+    util::env_var_with_fallback("PGM_SOCKET", "PGMANAGER_SOCKET")
+        .unwrap_or_else(|| DEFAULT_SOCKET_PATH.to_string())
+}
+
+async fn connect() -> UnixStream {
+    connect_at(Path::new(&socket_path())).await
+}
+
+/// Connect to `path`, retrying as configured with `PGM_CONNECT_ATTEMPTS`.
+async fn connect_at(path: &Path) -> UnixStream {
+    connect_with_backoff(path, connect_attempts(), CONNECT_RETRY_DELAY)
+        .await
+        .map_err(PgmError::Connect)
+        .unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// The uid the socket must belong to, from `PGM_VERIFY_SOCKET_OWNER`
+fn expected_socket_owner() -> Option<u32> {
+    match util::optional_env_var::<String>("VERIFY_SOCKET_OWNER")?.as_str() {
+        "false" => None,
+        "true" => Some(util::current_uid()),
+        uid => Some(uid.parse().unwrap_or_else(|_| {
+            panic!("PGM_VERIFY_SOCKET_OWNER must be true, false or a uid, got {uid:?}")
+        })),
+    }
+}
+
+/// Refuse to talk to a socket created by another user, which could be an impostor server.
+fn verify_socket_owner(path: &Path, uid: u32) -> std::io::Result<()> {
+    let owner = std::fs::metadata(path)?.uid();
+    if owner != uid {
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Socket {} is owned by uid {} rather than {}",
+                path.display(),
+                owner,
+                uid
+            ),
+        ));
+    }
+    Ok(())
+}
+
+async fn send_request(stream: &mut UnixStream, request: Request) {
+    stream
+        .write_all(request.to_string().as_bytes())
+        .await
+        .expect("Failed to write to test manager socket");
+}
+
+fn client_read_timeout() -> Option<Duration> {
+    util::optional_env_var("CLIENT_READ_TIMEOUT_MS").map(Duration::from_millis)
+}
+
+async fn read_response(stream: &mut UnixStream) -> String {
+    read_response_timeout(stream, client_read_timeout()).await
+}
+
+async fn read_response_timeout(stream: &mut UnixStream, timeout: Option<Duration>) -> String {
+    match try_read_response(stream, timeout).await {
+        Ok(response) => response,
+        Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::UnexpectedEof) => {
+            panic!("{e}")
+        }
+        Err(e) => panic!("Failed to read from test manager socket: {e:?}"),
+    }
+}
+
+async fn try_read_response(
+    stream: &mut UnixStream,
+    timeout: Option<Duration>,
+) -> std::io::Result<String> {
+    let line = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, protocol::read_line(stream))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "Timed out after {}ms waiting for test manager",
+                        timeout.as_millis()
+                    ),
+                )
+            })?,
+        None => protocol::read_line(stream).await,
+    };
+    line.map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "Test manager socket closed unexpectedly",
+        ),
+        _ => e,
+    })
+}
+
+/// Errors worth reconnecting for, e.g. when the server restarts mid-handshake
+fn is_transient(error: &PgmError) -> bool {
+    match error {
+        PgmError::Connect(e) | PgmError::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::NotFound
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::BrokenPipe
+        ),
+        PgmError::Closed => true,
+        _ => false,
+    }
+}
+
+/// Lease a database, panicking if none can be leased. See [`try_get_database`].
 pub async fn get_database() -> DatabaseGuard {
-    let path = util::env_var_with_fallback("PGM_SOCKET", "PGMANAGER_SOCKET")
-        .unwrap_or_else(|| DEFAULT_SOCKET_PATH.to_string());
-    let stream = tokio::net::UnixStream::connect(path)
+    try_get_database().await.unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// Like [`get_database`], but returns an error instead of panicking when the
+/// manager can't be reached or doesn't assign a database.
+pub async fn try_get_database() -> Result<DatabaseGuard, PgmError> {
+    try_get_database_at(Path::new(&socket_path())).await
+}
+
+/// Like [`get_database`], but returns `None` straight away if there is no
+/// manager socket, so tests can skip themselves when run without pgmanager.
+pub async fn get_database_or_skip() -> Option<DatabaseGuard> {
+    get_database_or_skip_at(Path::new(&socket_path())).await
+}
+
+async fn get_database_or_skip_at(path: &Path) -> Option<DatabaseGuard> {
+    if !path.exists() {
+        info!(
+            "No test manager socket at {}, skipping database",
+            path.display()
+        );
+        return None;
+    }
+    Some(get_database_at(path).await)
+}
+
+/// Like [`get_database`], but reconnects and tries again up to `attempts` times
+/// if the connection fails or is reset before a database is assigned, backing
+/// off from 10ms between attempts. Panics once the attempts run out; see
+/// [`get_database_connect_retry`] to only retry the connect and get an error.
+pub async fn get_database_with_retry(attempts: u32) -> DatabaseGuard {
+    get_database_with_retry_at(Path::new(&socket_path()), attempts).await
+}
+
+async fn get_database_with_retry_at(path: &Path, attempts: u32) -> DatabaseGuard {
+    let mut backoff = CONNECT_RETRY_DELAY;
+    for attempt in 1.. {
+        match get_database_connect_retry_at(path, 1, CONNECT_RETRY_DELAY).await {
+            Ok(guard) => return guard,
+            Err(e) if is_transient(&e) && attempt < attempts => {
+                warn!("Retrying test database acquire after error: {}", e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => panic!("Failed to acquire test database: {e}"),
+        }
+    }
+    unreachable!()
+}
+
+/// First delay between connection attempts, doubling after each one
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// How many times plain acquires try to connect, from `PGM_CONNECT_ATTEMPTS`
+fn connect_attempts() -> u32 {
+    util::optional_env_var("CONNECT_ATTEMPTS").unwrap_or(1)
+}
+
+/// Connect to the socket at `path`, trying up to `attempts` times while it
+/// doesn't exist yet or refuses connections, backing off from `base_delay`.
+async fn connect_with_backoff(
+    path: &Path,
+    attempts: u32,
+    base_delay: Duration,
+) -> std::io::Result<UnixStream> {
+    let mut backoff = base_delay;
+    for attempt in 1.. {
+        let connected = match expected_socket_owner() {
+            Some(uid) => verify_socket_owner(path, uid),
+            None => Ok(()),
+        };
+        let error = match connected {
+            Ok(()) => match UnixStream::connect(path).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => e,
+            },
+            Err(e) => e,
+        };
+        if attempt >= attempts
+            || !matches!(
+                error.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            )
+        {
+            return Err(error);
+        }
+        debug!("Retrying connection to test manager after error: {}", error);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+    unreachable!()
+}
+
+async fn try_get_database_at(path: &Path) -> Result<DatabaseGuard, PgmError> {
+    get_database_connect_retry_at(path, connect_attempts(), CONNECT_RETRY_DELAY).await
+}
+
+/// Like [`try_get_database`], but tries to connect up to `attempts` times,
+/// backing off from `base_delay`, while the socket doesn't exist yet or
+/// refuses connections, e.g. when a wrapped command starts before the server
+/// is listening. Once connected, nothing is retried: an `EMPTY` answer is
+/// returned as [`PgmError::Empty`] straight away, and a reset connection
+/// is an error rather than a reason to retry as in [`get_database_with_retry`].
+pub async fn get_database_connect_retry(
+    attempts: u32,
+    base_delay: Duration,
+) -> Result<DatabaseGuard, PgmError> {
+    get_database_connect_retry_at(Path::new(&socket_path()), attempts, base_delay).await
+}
+
+async fn get_database_connect_retry_at(
+    path: &Path,
+    attempts: u32,
+    base_delay: Duration,
+) -> Result<DatabaseGuard, PgmError> {
+    let mut stream = connect_with_backoff(path, attempts, base_delay)
+        .await
+        .map_err(PgmError::Connect)?;
+    let request = Request::Acquire(AcquireOptions::default());
+    stream.write_all(request.to_string().as_bytes()).await?;
+    let timeout = client_read_timeout();
+    let response = try_read_response(&mut stream, timeout)
         .await
-        .expect("Failed to connect to test manager socket");
-    get_database_from_stream(stream).await
+        .map_err(|e| match timeout {
+            Some(timeout) if e.kind() == ErrorKind::TimedOut => PgmError::Timeout(timeout),
+            _ => e.into(),
+        })?;
+    try_guard_from_response(stream, &response)
 }
 
-async fn get_database_from_stream(mut stream: UnixStream) -> DatabaseGuard {
-    let mut buffer = [0; 1024];
-    let read = stream
-        .read(&mut buffer)
+/// Like [`try_get_database`], but gives up with [`PgmError::Timeout`] if
+/// connecting and being assigned a database takes longer than `timeout`,
+/// e.g. when the pool stays exhausted or the manager never answers.
+pub async fn get_database_timeout(timeout: Duration) -> Result<DatabaseGuard, PgmError> {
+    get_database_timeout_at(Path::new(&socket_path()), timeout).await
+}
+
+async fn get_database_timeout_at(
+    path: &Path,
+    timeout: Duration,
+) -> Result<DatabaseGuard, PgmError> {
+    tokio::time::timeout(timeout, try_get_database_at(path))
         .await
-        .expect("Failed to read from test manager socket");
-    if read == 0 {
-        panic!("Test manager socket closed unexpectedly");
+        .map_err(|_| PgmError::Timeout(timeout))?
+}
+
+/// Wait until a server accepts connections at `path`, polling every 10ms.
+///
+/// Fails with [`std::io::ErrorKind::TimedOut`] if it isn't listening within `timeout`.
+pub async fn wait_until_listening(
+    path: impl AsRef<Path>,
+    timeout: Duration,
+) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let deadline = Instant::now() + timeout;
+    loop {
+        let error = match UnixStream::connect(path).await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        if Instant::now() >= deadline {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "No server listening at {} after {}ms: {}",
+                    path.display(),
+                    timeout.as_millis(),
+                    error
+                ),
+            ));
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Like [`get_database`], but also writes `PGDATABASE=<name>` to `path` for
+/// tools that read their settings from a file. The file is removed when the
+/// guard is dropped.
+pub async fn get_database_to_file(path: impl AsRef<Path>) -> DatabaseGuard {
+    let mut guard = get_database().await;
+    guard
+        .write_env_file(path.as_ref())
+        .expect("Failed to write database env file");
+    guard
+}
+
+/// Like [`get_database`], but logs how long the database was held when released.
+pub async fn get_database_timed() -> TimedGuard {
+    get_database().await.into()
+}
+
+/// Like [`get_database`], but the database is released after `max_hold` even
+/// if the guard is still alive, logging a warning. Protects the pool from
+/// tests that hold on to their guard for too long.
+pub async fn get_database_with_max_hold(max_hold: Duration) -> DatabaseGuard {
+    let mut guard = get_database().await;
+    guard
+        .spawn_watchdog(max_hold)
+        .expect("Failed to start database watchdog");
+    guard
+}
+
+/// Like [`get_database`], but may use databases the server holds back for
+/// priority clients with `PGM_RESERVE_FREE`.
+pub async fn get_database_priority() -> DatabaseGuard {
+    let options = AcquireOptions {
+        priority: true,
+        ..Default::default()
+    };
+    acquire_from_stream(connect().await, Request::Acquire(options)).await
+}
+
+/// Lease a database tagged with all of `capabilities` in `PGM_DATABASE_CAPABILITIES`.
+///
+/// Waits for a matching database to be free, and panics if none in the pool matches.
+pub async fn get_database_with_capabilities(capabilities: &[&str]) -> DatabaseGuard {
+    let options = AcquireOptions {
+        capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        ..Default::default()
+    };
+    acquire_from_stream(connect().await, Request::Acquire(options)).await
+}
+
+/// Lease the database called `name`, for fixtures that expect the same
+/// database throughout a run. Waits for it if another client holds it, and
+/// falls back to any free database if the pool has none by that name.
+pub async fn get_database_named(name: &str) -> DatabaseGuard {
+    let options = AcquireOptions {
+        name: Some(name.to_string()),
+        ..Default::default()
+    };
+    acquire_from_stream(connect().await, Request::Acquire(options)).await
+}
+
+/// Lease up to `n` databases one after another, yielding each as the server
+/// assigns it. The next acquire waits until the previous guard is taken from
+/// the stream, so a full pool holds the stream back until a guard drops.
+/// An acquire that fails is yielded as the error and ends the stream.
+pub fn database_stream(n: usize) -> DatabaseStream {
+    database_stream_at(PathBuf::from(socket_path()), n)
+}
+
+fn database_stream_at(path: PathBuf, n: usize) -> DatabaseStream {
+    let (sender, receiver) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(async move {
+        for _ in 0..n {
+            let result = try_get_database_at(&path).await;
+            let failed = result.is_err();
+            if sender.send(result).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    DatabaseStream { receiver }
+}
+
+/// Lease a database shared by every client passing the same `group` token,
+/// e.g. the frontend, backend and worker of one end-to-end test. The database
+/// is returned to the pool once the last of them releases it.
+pub async fn get_database_in_group(group: &str) -> DatabaseGuard {
+    let options = AcquireOptions {
+        group: Some(group.to_string()),
+        ..Default::default()
+    };
+    acquire_from_stream(connect().await, Request::Acquire(options)).await
+}
+
+/// Like [`get_database`], but never waits: returns `None` straight away if no
+/// database is free.
+pub async fn get_database_nowait() -> Option<DatabaseGuard> {
+    get_database_nowait_from_stream(connect().await).await
+}
+
+async fn get_database_nowait_from_stream(mut stream: UnixStream) -> Option<DatabaseGuard> {
+    let options = AcquireOptions {
+        nowait: true,
+        ..Default::default()
+    };
+    send_request(&mut stream, Request::Acquire(options)).await;
+    let response = read_response(&mut stream).await;
+    if response.starts_with("EMPTY:") {
+        return None;
     }
-    let response = String::from_utf8_lossy(&buffer);
+    Some(guard_from_response(stream, &response))
+}
+
+async fn get_database_at(path: &Path) -> DatabaseGuard {
+    try_get_database_at(path)
+        .await
+        .unwrap_or_else(|e| panic!("{e}"))
+}
+
+#[cfg(test)]
+async fn get_database_from_stream(stream: UnixStream) -> DatabaseGuard {
+    acquire_from_stream(stream, Request::Acquire(AcquireOptions::default())).await
+}
+
+async fn acquire_from_stream(mut stream: UnixStream, request: Request) -> DatabaseGuard {
+    send_request(&mut stream, request).await;
+    let response = read_response(&mut stream).await;
+    guard_from_response(stream, &response)
+}
+
+fn guard_from_response(stream: UnixStream, response: &str) -> DatabaseGuard {
+    try_guard_from_response(stream, response).unwrap_or_else(|e| panic!("{e}"))
+}
+
+fn try_guard_from_response(stream: UnixStream, response: &str) -> Result<DatabaseGuard, PgmError> {
     let (prefix, message) = response.split_once(':').unwrap_or(("", ""));
     match (prefix, message) {
         ("OK", db_name) => {
-            let db_name = db_name.replace('\0', "");
+            let db_name = db_name.to_string();
 
             eprintln!("Using test database: {}", db_name);
-            DatabaseGuard {
+            Ok(DatabaseGuard {
                 name: db_name,
-                _stream: stream,
+                stream,
+                env_file: None,
+                _watchdog: None,
+            })
+        }
+        ("EMPTY", message) => Err(PgmError::Empty(message.to_string())),
+        ("ERROR", message) => Err(PgmError::Refused(message.to_string())),
+        (_, _) => Err(PgmError::InvalidResponse(response.to_string())),
+    }
+}
+
+/// Hold `guard` while running `future`, making its database name available to
+/// anything in the task through [`current_database`].
+pub async fn with_current_database<F: Future>(guard: DatabaseGuard, future: F) -> F::Output {
+    let output = CURRENT_DATABASE.scope(guard.name.clone(), future).await;
+    drop(guard);
+    output
+}
+
+/// The database set by the enclosing [`with_current_database`], if any.
+pub fn current_database() -> Option<String> {
+    CURRENT_DATABASE.try_with(String::clone).ok()
+}
+
+/// Lease a database by creating `<key>.lock` in the server's `PGM_LOCK_DIR`,
+/// for runners that coordinate through lock files rather than the socket.
+///
+/// Waits for the server to write the assigned name to `<key>.db`, giving up
+/// with [`PgmError::Timeout`] and removing the lock file after `timeout`.
+pub async fn get_database_with_lock_file(
+    dir: impl AsRef<Path>,
+    key: &str,
+    timeout: Option<Duration>,
+) -> Result<LockFileGuard, PgmError> {
+    let lock_file = dir.as_ref().join(format!("{key}.lock"));
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&lock_file)
+        .map_err(PgmError::Io)?;
+    let db_file = lock_file.with_extension("db");
+    // Removes the lock file again if we give up before a name is assigned
+    let mut guard = LockFileGuard {
+        name: String::new(),
+        lock_file,
+    };
+    let assigned = async {
+        loop {
+            match std::fs::read_to_string(&db_file) {
+                Ok(name) if !name.is_empty() => break name,
+                _ => tokio::time::sleep(Duration::from_millis(10)).await,
             }
         }
-        ("EMPTY", message) => {
+    };
+    guard.name = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, assigned)
+            .await
+            .map_err(|_| PgmError::Timeout(timeout))?,
+        None => assigned.await,
+    };
+    eprintln!("Using test database: {}", guard.name);
+    Ok(guard)
+}
+
+/// Lease a database that stays leased after the connection closes.
+///
+/// The caller is responsible for passing the lease to [`release`] when done.
+pub async fn lease_detached() -> DetachedLease {
+    lease_detached_from_stream(connect().await).await
+}
+
+async fn lease_detached_from_stream(mut stream: UnixStream) -> DetachedLease {
+    send_request(&mut stream, Request::Detach).await;
+    let response = read_response(&mut stream).await;
+    match response.split_once(':') {
+        Some(("DETACHED", lease)) => {
+            let (token, db_name) = lease.split_once(':').unwrap_or((lease, ""));
+            eprintln!("Using detached test database: {}", db_name);
+            DetachedLease {
+                name: db_name.to_string(),
+                token: token.to_string(),
+            }
+        }
+        Some(("EMPTY", message)) => {
             panic!("No databases available: {message}");
         }
-        (_, _) => {
+        _ => {
+            panic!("Unexpected response from test manager: {response}")
+        }
+    }
+}
+
+/// Return a detached lease to the pool.
+pub async fn release(lease: DetachedLease) {
+    release_from_stream(connect().await, lease).await
+}
+
+async fn release_from_stream(mut stream: UnixStream, lease: DetachedLease) {
+    send_request(&mut stream, Request::Release(lease.token)).await;
+    let response = read_response(&mut stream).await;
+    match response.split_once(':') {
+        Some(("OK", _)) => {}
+        Some(("ERROR", message)) => {
+            panic!("Failed to release {}: {message}", lease.name);
+        }
+        _ => {
+            panic!("Unexpected response from test manager: {response}")
+        }
+    }
+}
+
+/// Ask the server at `path` to force-release `name`, returning its error if it wasn't leased.
+async fn kill_lease_at(path: &Path, name: &str) -> Result<(), String> {
+    let mut stream = connect_at(path).await;
+    send_request(&mut stream, Request::Kill(name.to_string())).await;
+    let response = read_response(&mut stream).await;
+    match response.split_once(':') {
+        Some(("OK", _)) => Ok(()),
+        Some(("ERROR", message)) => Err(message.to_string()),
+        _ => {
+            panic!("Unexpected response from test manager: {response}")
+        }
+    }
+}
+
+/// Ask the server at `path` to shut down.
+async fn shutdown_at(path: &Path) -> Result<(), String> {
+    let mut stream = connect_at(path).await;
+    send_request(&mut stream, Request::Shutdown).await;
+    let response = read_response(&mut stream).await;
+    match response.split_once(':') {
+        Some(("OK", _)) => Ok(()),
+        Some(("ERROR", message)) => Err(message.to_string()),
+        _ => {
+            panic!("Unexpected response from test manager: {response}")
+        }
+    }
+}
+
+/// Ask the server at `path` for its usage, resetting the peak and wait counters if `reset` is set.
+async fn server_stats_at(path: &Path, reset: bool) -> stats::StatsSnapshot {
+    let mut stream = connect_at(path).await;
+    send_request(&mut stream, Request::Stats { reset }).await;
+    let response = read_response(&mut stream).await;
+    match response.split_once(':') {
+        Some(("OK", snapshot)) => stats::StatsSnapshot::parse(snapshot)
+            .unwrap_or_else(|| panic!("Unexpected stats from test manager: {snapshot}")),
+        _ => {
             panic!("Unexpected response from test manager: {response}")
         }
     }
@@ -109,11 +854,635 @@ pub(crate) mod tests {
         cancellation_token.cancel();
         server.await.expect("Server task failed");
     }
+
+    #[tokio::test]
+    #[should_panic(expected = "Timed out after 50ms waiting for test manager")]
+    async fn test_read_timeout() {
+        let path = test_helpers::temp_path();
+        let listener = tokio::net::UnixListener::bind(&path).expect("Failed to bind");
+        let mut stream = test_helpers::temp_client(&path).await;
+        // Accept the connection but never respond
+        let _accepted = listener.accept().await.expect("Failed to accept");
+        read_response_timeout(&mut stream, Some(Duration::from_millis(50))).await;
+    }
+
+    #[tokio::test]
+    async fn test_timed_guard_logs_held_duration() {
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) = test_helpers::temp_server(&path, None).await;
+
+        let stream = test_helpers::temp_client(&path).await;
+        let db_guard = TimedGuard::from(get_database_from_stream(stream).await);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let name = db_guard.name.clone();
+        assert!(db_guard.held() >= Duration::from_millis(5));
+
+        let logs = test_helpers::capture_logs(|| drop(db_guard));
+        assert!(logs.contains(&format!("Releasing test database: {name} after")));
+        assert!(!logs.contains(" after 0ms"));
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_listening() {
+        let path = test_helpers::temp_path();
+        let err = wait_until_listening(&path, Duration::from_millis(20))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        let starting = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                test_helpers::temp_server(&path, None).await
+            })
+        };
+        wait_until_listening(&path, Duration::from_secs(1))
+            .await
+            .expect("Server did not start listening");
+        let (server, cancellation_token) = starting.await.unwrap();
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "No databases available: No database has capabilities timescale")]
+    async fn test_unmatched_capabilities() {
+        let path = test_helpers::temp_path();
+        let (_server, _cancellation_token) = test_helpers::temp_server(&path, None).await;
+
+        let stream = test_helpers::temp_client(&path).await;
+        let options = AcquireOptions {
+            capabilities: ["timescale".to_string()].into(),
+            ..Default::default()
+        };
+        acquire_from_stream(stream, Request::Acquire(options)).await;
+    }
+
+    #[tokio::test]
+    async fn test_env_file_removed_on_release() {
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(1, "test_db".into()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+        let env_file = test_helpers::temp_path();
+
+        let stream = test_helpers::temp_client(&path).await;
+        let mut db_guard = get_database_from_stream(stream).await;
+        db_guard.write_env_file(&env_file).unwrap();
+        let contents = std::fs::read_to_string(&env_file).unwrap();
+        assert_eq!(contents, "PGDATABASE=test_db0\n");
+
+        drop(db_guard);
+        assert!(!env_file.exists());
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_max_hold_releases_database() {
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(1, "test_db".into()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+
+        let stream = test_helpers::temp_client(&path).await;
+        let mut held = get_database_from_stream(stream).await;
+        held.spawn_watchdog(Duration::from_millis(50)).unwrap();
+
+        // Acquired while `held` is still alive
+        let stream = test_helpers::temp_client(&path).await;
+        let db_guard =
+            tokio::time::timeout(Duration::from_secs(1), get_database_from_stream(stream))
+                .await
+                .expect("Database was not released after max hold");
+        assert_eq!(db_guard.name, held.name);
+        drop(db_guard);
+        drop(held);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_current_database() {
+        async fn nested_helper() -> Option<String> {
+            current_database()
+        }
+
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(1, "test_db".into()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+        assert_eq!(current_database(), None);
+
+        let stream = test_helpers::temp_client(&path).await;
+        let db_guard = get_database_from_stream(stream).await;
+        let name = with_current_database(db_guard, nested_helper()).await;
+        assert_eq!(name.as_deref(), Some("test_db0"));
+        assert_eq!(current_database(), None);
+
+        // The guard was released when the scope ended
+        let stream = test_helpers::temp_client(&path).await;
+        get_database_from_stream(stream).await;
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_get_database_or_skip() {
+        let path = test_helpers::temp_path();
+        assert!(get_database_or_skip_at(&path).await.is_none());
+
+        let config = Some(core::Config::new(1, "test_db".into()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+        let db_guard = get_database_or_skip_at(&path).await;
+        assert_eq!(db_guard.as_deref(), Some("test_db0"));
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_connection_reset() {
+        let path = test_helpers::temp_path();
+        let listener = tokio::net::UnixListener::bind(&path).expect("Failed to bind");
+        let fake_server = tokio::spawn(async move {
+            // Drop the first connection as if the server restarted mid-handshake
+            drop(listener.accept().await.unwrap());
+            let (mut stream, _) = listener.accept().await.unwrap();
+            test_helpers::read_request_line(&mut stream).await;
+            stream.write_all(b"OK:test_db0\n").await.unwrap();
+            stream
+        });
+
+        let db_guard = get_database_with_retry_at(&path, 3).await;
+        assert_eq!(db_guard.name, "test_db0");
+        drop(fake_server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_back_to_back_responses() {
+        let path = test_helpers::temp_path();
+        let listener = tokio::net::UnixListener::bind(&path).expect("Failed to bind");
+        let fake_server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            test_helpers::read_request_line(&mut stream).await;
+            stream
+                .write_all(b"OK:test_db0\nOK:test_db1\n")
+                .await
+                .unwrap();
+            stream
+        });
+
+        let stream = test_helpers::temp_client(&path).await;
+        let mut db_guard = get_database_from_stream(stream).await;
+        assert_eq!(db_guard.name, "test_db0");
+        let response = try_read_response(&mut db_guard.stream, None).await.unwrap();
+        assert_eq!(response, "OK:test_db1");
+        drop(fake_server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_try_get_database_errors() {
+        async fn respond_with(response: Option<&'static str>) -> Result<DatabaseGuard, PgmError> {
+            let path = test_helpers::temp_path();
+            let listener = tokio::net::UnixListener::bind(&path).expect("Failed to bind");
+            let fake_server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                test_helpers::read_request_line(&mut stream).await;
+                let response = response?;
+                stream
+                    .write_all(format!("{response}\n").as_bytes())
+                    .await
+                    .unwrap();
+                Some(stream)
+            });
+            let result = try_get_database_at(&path).await;
+            drop(fake_server.await.unwrap());
+            result
+        }
+
+        let err = try_get_database_at(&test_helpers::temp_path())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(&err, PgmError::Connect(e) if e.kind() == ErrorKind::NotFound));
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err = respond_with(None).await.err().unwrap();
+        assert!(matches!(err, PgmError::Closed));
+        let err = respond_with(Some("EMPTY:No databases available"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "No databases available: No databases available"
+        );
+        let err = respond_with(Some("ERROR:Too many connections (max 1)"))
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, PgmError::Refused(message) if message == "Too many connections (max 1)")
+        );
+        let err = respond_with(Some("HELLO")).await.err().unwrap();
+        assert!(matches!(err, PgmError::InvalidResponse(response) if response == "HELLO"));
+        assert!(respond_with(Some("OK:test_db0")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_database_timeout() {
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(1, "test_db".into()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+
+        let held = get_database_timeout_at(&path, Duration::from_secs(1))
+            .await
+            .expect("Free database was not assigned");
+        // The pool is exhausted, so the server keeps the connection waiting
+        let err = get_database_timeout_at(&path, Duration::from_millis(50))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, PgmError::Timeout(timeout) if timeout.as_millis() == 50));
+        assert_eq!(
+            err.to_string(),
+            "Timed out after 50ms waiting for test manager"
+        );
+        drop(held);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    /// The client `test_connect_attempts_apply_to_every_acquire` runs before the server starts
+    #[tokio::test]
+    #[ignore = "run with PGM_CONNECT_ATTEMPTS by test_connect_attempts_apply_to_every_acquire"]
+    async fn acquire_before_server_starts() {
+        if std::env::var_os("PGM_TEST_CONNECT_ATTEMPTS").is_none() {
+            return;
+        }
+        assert_eq!(get_database_named("test_db_1").await.name, "test_db_1");
+        drop(get_database_priority().await);
+        let lease = lease_detached().await;
+        release(lease).await;
+        assert!(get_database_nowait().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_connect_attempts_apply_to_every_acquire() {
+        let path = test_helpers::temp_path();
+        let client = tokio::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "tests::acquire_before_server_starts",
+                "--exact",
+                "--ignored",
+                "--quiet",
+            ])
+            .env("PGM_TEST_CONNECT_ATTEMPTS", "1")
+            .env("PGM_CONNECT_ATTEMPTS", "10")
+            .env("PGM_SOCKET", &path)
+            .env_remove("PGM_STRICT_ENV")
+            .spawn()
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (server, cancellation_token) = test_helpers::temp_server(&path, None).await;
+
+        let output = client.wait_with_output().await.unwrap();
+        assert!(
+            output.status.success(),
+            "Client failed before the server started"
+        );
+        cancellation_token.cancel();
+        let stats = server.await.expect("Server task failed");
+        assert_eq!(stats.peak_usage(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_database_connect_retry() {
+        let path = test_helpers::temp_path();
+        let err = get_database_connect_retry_at(&path, 1, Duration::from_millis(10))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, PgmError::Connect(e) if e.kind() == ErrorKind::NotFound));
+
+        let starting = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                test_helpers::temp_server(&path, None).await
+            })
+        };
+        let db_guard = get_database_connect_retry_at(&path, 10, Duration::from_millis(10))
+            .await
+            .expect("Did not connect once the server was listening");
+        assert!(db_guard.name.starts_with("test_db_"));
+        drop(db_guard);
+        let (server, cancellation_token) = starting.await.unwrap();
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+
+        // An answer from the server is never retried
+        let path = test_helpers::temp_path();
+        let listener = tokio::net::UnixListener::bind(&path).expect("Failed to bind");
+        let fake_server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            test_helpers::read_request_line(&mut stream).await;
+            stream
+                .write_all(b"EMPTY:No databases available\n")
+                .await
+                .unwrap();
+            listener
+        });
+        let err = get_database_connect_retry_at(&path, 3, Duration::from_millis(10))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, PgmError::Empty(_)));
+        let listener = fake_server.await.unwrap();
+        let another = tokio::time::timeout(Duration::from_millis(50), listener.accept()).await;
+        assert!(another.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_database_stream() {
+        use futures_util::StreamExt as _;
+
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) = test_helpers::temp_server(&path, None).await;
+
+        let mut databases = database_stream_at(path.clone(), 3);
+        let first = databases.next().await.unwrap().unwrap();
+        let second = databases.next().await.unwrap().unwrap();
+        assert_ne!(first.name, second.name);
+
+        // The pool of 2 is exhausted until a guard drops
+        let pending = tokio::time::timeout(Duration::from_millis(50), databases.next()).await;
+        assert!(pending.is_err());
+        let name = first.name.clone();
+        drop(first);
+        let third = databases.next().await.unwrap().unwrap();
+        assert_eq!(third.name, name);
+        assert!(databases.next().await.is_none());
+
+        drop((second, third));
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+
+        // Failing to connect is yielded, not swallowed
+        let mut databases = database_stream_at(test_helpers::temp_path(), 3);
+        let err = databases.next().await.unwrap().err().unwrap();
+        assert!(matches!(err, PgmError::Connect(_)));
+        assert!(databases.next().await.is_none());
+    }
+
+    #[test]
+    fn test_verify_socket_owner() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let uid = std::fs::metadata(file.path()).unwrap().uid();
+        verify_socket_owner(file.path(), uid).expect("Own file was rejected");
+
+        let err = verify_socket_owner(file.path(), uid + 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains(&format!(
+            "is owned by uid {} rather than {}",
+            uid,
+            uid + 1
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_lock_file_lease() {
+        let path = test_helpers::temp_path();
+        let lock_dir = tempfile::tempdir().unwrap();
+        let config = core::Config::new(1, "test_db".into()).with_lock_dir(lock_dir.path().into());
+        let (server, cancellation_token) = test_helpers::temp_server(&path, Some(config)).await;
+
+        let locked = get_database_with_lock_file(lock_dir.path(), "spec_a", None)
+            .await
+            .unwrap();
+        assert_eq!(locked.name, "test_db0");
+        let lock_file = lock_dir.path().join("spec_a.lock");
+        assert!(lock_file.exists());
+
+        // The only database is held through the lock file
+        let stream = test_helpers::temp_client(&path).await;
+        let pending =
+            tokio::time::timeout(Duration::from_millis(50), get_database_from_stream(stream)).await;
+        assert!(pending.is_err());
+
+        drop(locked);
+        assert!(!lock_file.exists());
+        let stream = test_helpers::temp_client(&path).await;
+        let db_guard =
+            tokio::time::timeout(Duration::from_secs(1), get_database_from_stream(stream))
+                .await
+                .expect("Database was not released with the lock file");
+        assert_eq!(db_guard.name, "test_db0");
+        drop(db_guard);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_lock_file_lease_timeout() {
+        // No server is watching the directory, so no name is ever assigned
+        let lock_dir = tempfile::tempdir().unwrap();
+        let result =
+            get_database_with_lock_file(lock_dir.path(), "spec_a", Some(Duration::from_millis(50)))
+                .await;
+        assert!(matches!(result, Err(PgmError::Timeout(_))));
+        assert!(!lock_dir.path().join("spec_a.lock").exists());
+
+        let missing = lock_dir.path().join("missing");
+        let result = get_database_with_lock_file(&missing, "spec_a", None).await;
+        assert!(matches!(result, Err(PgmError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_group_shares_database() {
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(1, "test_db".into()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+        let options = AcquireOptions {
+            group: Some("e2e".to_string()),
+            ..Default::default()
+        };
+
+        let mut members = Vec::new();
+        for _ in 0..3 {
+            let stream = test_helpers::temp_client(&path).await;
+            members.push(acquire_from_stream(stream, Request::Acquire(options.clone())).await);
+        }
+        assert!(members.iter().all(|member| member.name == "test_db0"));
+
+        while let Some(member) = members.pop() {
+            // The database stays leased until the last member disconnects
+            let stream = test_helpers::temp_client(&path).await;
+            let pending =
+                tokio::time::timeout(Duration::from_millis(50), get_database_from_stream(stream))
+                    .await;
+            assert!(pending.is_err());
+            drop(member);
+        }
+        let stream = test_helpers::temp_client(&path).await;
+        let db_guard =
+            tokio::time::timeout(Duration::from_secs(1), get_database_from_stream(stream))
+                .await
+                .expect("Group did not release its database");
+        assert_eq!(db_guard.name, "test_db0");
+        drop(db_guard);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_get_database_nowait() {
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(1, "test_db".into()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+
+        let stream = test_helpers::temp_client(&path).await;
+        let db_guard = get_database_nowait_from_stream(stream).await;
+        assert_eq!(db_guard.as_deref(), Some("test_db0"));
+
+        let stream = test_helpers::temp_client(&path).await;
+        let empty = tokio::time::timeout(
+            Duration::from_millis(100),
+            get_database_nowait_from_stream(stream),
+        )
+        .await
+        .expect("nowait acquire blocked on an empty pool");
+        assert!(empty.is_none());
+        drop(db_guard);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_release_returns_database() {
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(1, "test_db".into()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+
+        let db_guard = get_database_at(&path).await;
+        db_guard.release().await;
+        // Back in the pool as soon as release resolves
+        let stream = test_helpers::temp_client(&path).await;
+        let db_guard = get_database_nowait_from_stream(stream).await;
+        assert_eq!(db_guard.as_deref(), Some("test_db0"));
+
+        let options = AcquireOptions {
+            group: Some("e2e".to_string()),
+            ..Default::default()
+        };
+        drop(db_guard);
+        let stream = test_helpers::temp_client(&path).await;
+        let member = acquire_from_stream(stream, Request::Acquire(options)).await;
+        member.release().await;
+        let stream = test_helpers::temp_client(&path).await;
+        let db_guard = get_database_nowait_from_stream(stream).await;
+        assert_eq!(db_guard.as_deref(), Some("test_db0"));
+        drop(db_guard);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_get_database_named() {
+        async fn acquire_named(path: &Path, name: &str) -> DatabaseGuard {
+            let options = AcquireOptions {
+                name: Some(name.to_string()),
+                ..Default::default()
+            };
+            let stream = test_helpers::temp_client(path).await;
+            acquire_from_stream(stream, Request::Acquire(options)).await
+        }
+
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) = test_helpers::temp_server(&path, None).await;
+
+        let named = acquire_named(&path, "test_db_1").await;
+        assert_eq!(named.name, "test_db_1");
+        // Waits for the named database rather than taking the free one
+        let pending =
+            tokio::time::timeout(Duration::from_millis(50), acquire_named(&path, "test_db_1"))
+                .await;
+        assert!(pending.is_err());
+        let waiting = {
+            let path = path.clone();
+            tokio::spawn(async move { acquire_named(&path, "test_db_1").await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(named);
+        let named = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("Named database was not handed over")
+            .unwrap();
+        assert_eq!(named.name, "test_db_1");
+
+        let fallback = acquire_named(&path, "other_db").await;
+        assert_eq!(fallback.name, "test_db_0");
+        drop((named, fallback));
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_server_stats_reset() {
+        let path = test_helpers::temp_path();
+        let (server, cancellation_token) = test_helpers::temp_server(&path, None).await;
+
+        let held = get_database_at(&path).await;
+        drop(get_database_at(&path).await);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let snapshot = server_stats_at(&path, true).await;
+        assert_eq!(snapshot.usage, 1);
+        assert_eq!(snapshot.peak_usage, 2);
+        // The held lease and the stats request itself
+        assert_eq!(snapshot.connections, 2);
+        let snapshot = server_stats_at(&path, false).await;
+        assert_eq!(snapshot.usage, 1);
+        assert_eq!(snapshot.peak_usage, 1);
+        assert_eq!(snapshot.total_wait_ms, 0);
+        drop(held);
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
+
+    #[tokio::test]
+    async fn test_lease_detached() {
+        let path = test_helpers::temp_path();
+        let config = Some(core::Config::new(1, "test_db".into()));
+        let (server, cancellation_token) = test_helpers::temp_server(&path, config).await;
+
+        let stream = test_helpers::temp_client(&path).await;
+        let lease = lease_detached_from_stream(stream).await;
+        assert_eq!(lease.name, "test_db0");
+
+        // The connection is closed but the database is still leased
+        let stream = test_helpers::temp_client(&path).await;
+        let pending = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            get_database_from_stream(stream),
+        )
+        .await;
+        assert!(pending.is_err());
+
+        let stream = test_helpers::temp_client(&path).await;
+        release_from_stream(stream, lease).await;
+
+        let stream = test_helpers::temp_client(&path).await;
+        let db_guard = get_database_from_stream(stream).await;
+        assert_eq!(db_guard.name, "test_db0");
+        cancellation_token.cancel();
+        server.await.expect("Server task failed");
+    }
 }
 
 #[cfg(test)]
 pub(crate) mod test_helpers {
-    use tokio::{net::UnixStream, task::JoinHandle};
+    use tokio::{io::AsyncWriteExt as _, net::UnixStream};
     use tokio_util::sync::CancellationToken;
 
     use crate::core;
@@ -128,15 +1497,63 @@ pub(crate) mod test_helpers {
     pub async fn temp_server(
         path: &std::path::Path,
         config: Option<core::Config>,
-    ) -> (JoinHandle<()>, CancellationToken) {
+    ) -> (core::ServerHandle, CancellationToken) {
         let config = config.unwrap_or_else(|| core::Config::new(2, "test_db_".to_string()));
         let (server, cancellation_token) = core::start_server(path, config).await;
         (server, cancellation_token)
     }
 
+    /// Run `f` with a debug subscriber installed and return what it logged
+    pub fn capture_logs(f: impl FnOnce()) -> String {
+        #[derive(Clone, Default)]
+        struct Capture(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let logs = capture.0.lock().unwrap();
+        String::from_utf8_lossy(&logs).into_owned()
+    }
+
     pub async fn temp_client(path: &std::path::Path) -> UnixStream {
         tokio::net::UnixStream::connect(path)
             .await
             .expect("Failed to connect")
     }
+
+    /// Send the request line `request` to the server at `path` and read its first response
+    pub async fn send_request(path: &std::path::Path, request: &str) -> (UnixStream, String) {
+        let mut stream = temp_client(path).await;
+        stream
+            .write_all(format!("{request}\n").as_bytes())
+            .await
+            .expect("Failed to send request");
+        let response = crate::protocol::read_line(&mut stream)
+            .await
+            .expect("Failed to read response");
+        (stream, response)
+    }
+
+    /// Read one request line from a client, as a fake server
+    pub async fn read_request_line(stream: &mut UnixStream) -> String {
+        crate::protocol::read_line(stream)
+            .await
+            .expect("Failed to read request")
+    }
 }
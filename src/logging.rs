@@ -0,0 +1,126 @@
+use std::io::Write;
+
+use tracing::Span;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A span naming this manager after `PGM_INSTANCE_NAME`, so logs from several
+/// managers can be told apart. Everything logged inside it carries an `instance` field.
+pub fn instance_span() -> Span {
+    named_span(crate::util::optional_env_var("INSTANCE_NAME"))
+}
+
+fn named_span(name: Option<String>) -> Span {
+    match name {
+        Some(name) => tracing::info_span!("pgmanager", instance = %name),
+        None => Span::none(),
+    }
+}
+
+/// Writes a prefix before every log line, to tell the server's logs apart
+/// from the output of a wrapped command sharing the same stderr.
+pub struct Prefixed<M> {
+    prefix: &'static str,
+    inner: M,
+}
+
+impl<M> Prefixed<M> {
+    pub fn new(prefix: &'static str, inner: M) -> Self {
+        Self { prefix, inner }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Prefixed<M> {
+    type Writer = PrefixedWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        PrefixedWriter {
+            prefix: self.prefix,
+            inner: self.inner.make_writer(),
+            started: false,
+        }
+    }
+}
+
+/// A writer for a single log line, made by [`Prefixed`].
+pub struct PrefixedWriter<W> {
+    prefix: &'static str,
+    inner: W,
+    started: bool,
+}
+
+impl<W: Write> Write for PrefixedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.started {
+            self.inner.write_all(self.prefix.as_bytes())?;
+            self.started = true;
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_prefixed_lines() {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(Prefixed::new("[pgmanager] ", move || writer.clone()))
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Listening");
+            // Output from the wrapped command bypasses the log writer
+            capture.clone().write_all(b"child output\n").unwrap();
+            tracing::info!("Shutting down server...");
+        });
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("[pgmanager] ") && lines[0].ends_with("Listening"));
+        assert_eq!(lines[1], "child output");
+        assert!(lines[2].starts_with("[pgmanager] "));
+    }
+
+    #[test]
+    fn test_instance_name_in_logs() {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            named_span(Some("ci-1".to_string())).in_scope(|| tracing::info!("Listening"));
+            named_span(None).in_scope(|| tracing::info!("Shutting down server..."));
+        });
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert!(lines[0].contains("pgmanager{instance=ci-1}"));
+        assert!(!lines[1].contains("instance="));
+    }
+}
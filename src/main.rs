@@ -1,8 +1,13 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use clap::{Parser, Subcommand};
+use tracing::Instrument as _;
 
-use pgmanager::commands;
+use pgmanager::{
+    commands,
+    logging::{self, Prefixed},
+};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
 #[derive(Parser)]
 struct Cli {
@@ -21,12 +26,22 @@ struct Cli {
 enum Commands {
     /// Serve the pgmanager socket
     #[command()]
-    Serve,
-    /// Wrap a command and pass PGMANAGER_SOCKET
+    Serve {
+        /// Wait for a server already listening on the socket to exit instead of failing
+        #[arg(long, default_value_t = false)]
+        wait: bool,
+    },
+    /// Wrap a command and pass PGM_SOCKET
     #[command()]
     Wrap {
         #[arg(last = true)]
         command: Vec<String>,
+        /// Lease N databases for the command's subprocesses, passed as PGM_DATABASE_0..N
+        #[arg(long, default_value_t = 0)]
+        reserve_for_children: usize,
+        /// Fail if the command never leased a database
+        #[arg(long, default_value_t = false)]
+        require_usage: bool,
     },
     /// Wrap a command n times passing PGM_SHARD and PGM_DATABASE_SHARD
     #[command()]
@@ -38,6 +53,67 @@ enum Commands {
         /// Pass the database name as an argument
         #[arg(short, long, default_value_t = false)]
         xarg: bool,
+        /// Pin databases to shards with a JSON object, e.g. '{"0": "myapp_test3"}'
+        #[arg(long)]
+        shard_map: Option<String>,
+        /// Fail if no shard ran and the commands never leased a database
+        #[arg(long, default_value_t = false)]
+        require_usage: bool,
+        /// Write each shard's database, exit code and duration to this JSON file
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Lease a database and print it as shell exports, holding it until interrupted
+    #[command()]
+    Env {
+        /// Release the database after this many seconds
+        #[arg(long)]
+        release_after: Option<u64>,
+    },
+    /// Force-release a leased database, closing the client connection holding it
+    #[command()]
+    KillLease { name: String },
+    /// Ask the running server to shut down
+    #[command()]
+    Shutdown {
+        /// Wait for the server to finish shutting down
+        #[arg(long, default_value_t = false)]
+        wait: bool,
+    },
+    /// Dump a reference database with pg_dump for `restore`
+    #[command()]
+    Snapshot {
+        name: String,
+        /// Where to write the dump, tmp/<name>.dump by default
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Load a snapshot into every database in the pool with pg_restore
+    #[command()]
+    Restore {
+        name: String,
+        /// Where to read the dump, tmp/<name>.dump by default
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Print the running server's usage statistics
+    #[command()]
+    Stats {
+        /// Reset the peak usage and wait time afterwards, e.g. between test phases
+        #[arg(long, default_value_t = false)]
+        reset: bool,
+    },
+    /// Lease a database, run a query against it and print the result
+    #[cfg(feature = "postgres")]
+    #[command()]
+    Query {
+        sql: String,
+        /// Print rows as a JSON array of objects
+        #[arg(long, default_value_t = false, conflicts_with = "csv")]
+        json: bool,
+        /// Print rows as CSV
+        #[arg(long, default_value_t = false)]
+        csv: bool,
     },
 }
 
@@ -45,16 +121,20 @@ enum Commands {
 async fn main() -> std::process::ExitCode {
     let args = Cli::parse();
 
+    // Server logs share stderr with the wrapped command, so mark them
+    let writer = match args.command {
+        Commands::Wrap { .. } | Commands::WrapEach { .. } => {
+            BoxMakeWriter::new(Prefixed::new("[pgmanager] ", std::io::stderr))
+        }
+        _ => BoxMakeWriter::new(std::io::stderr),
+    };
     tracing_subscriber::fmt()
         .with_max_level(if args.verbose {
             tracing::Level::DEBUG
         } else {
             tracing::Level::INFO
         })
-        .with_writer(
-            // Write to stderr
-            std::io::stderr,
-        )
+        .with_writer(writer)
         .init();
 
     let path = if args.socket.starts_with("/") {
@@ -63,16 +143,49 @@ async fn main() -> std::process::ExitCode {
         std::env::current_dir().unwrap().join(args.socket)
     };
 
-    match args.command {
-        Commands::Serve => {
-            commands::serve(&path).await;
-            std::process::ExitCode::SUCCESS
+    let command = async move {
+        match args.command {
+            Commands::Serve { wait } => commands::serve(&path, wait).await,
+            Commands::Wrap {
+                command,
+                reserve_for_children,
+                require_usage,
+            } => commands::wrap(&path, command, reserve_for_children, require_usage).await,
+            Commands::WrapEach {
+                command,
+                ignore_exit_code,
+                xarg,
+                shard_map,
+                require_usage,
+                report,
+            } => {
+                let options = commands::WrapEachOptions {
+                    ignore_exit_code,
+                    xarg,
+                    shard_map,
+                    require_usage,
+                    report,
+                };
+                commands::wrap_each(&path, command, options).await
+            }
+            Commands::Env { release_after } => {
+                commands::env(&path, release_after.map(Duration::from_secs)).await
+            }
+            Commands::KillLease { name } => commands::kill_lease(&path, &name).await,
+            Commands::Shutdown { wait } => commands::shutdown(&path, wait).await,
+            Commands::Stats { reset } => commands::stats(&path, reset).await,
+            Commands::Snapshot { name, file } => commands::snapshot(&name, file).await,
+            Commands::Restore { name, file } => commands::restore(&name, file).await,
+            #[cfg(feature = "postgres")]
+            Commands::Query { sql, json, csv } => {
+                let format = match (json, csv) {
+                    (true, _) => commands::OutputFormat::Json,
+                    (_, true) => commands::OutputFormat::Csv,
+                    _ => commands::OutputFormat::Table,
+                };
+                commands::query(&path, &sql, format).await
+            }
         }
-        Commands::Wrap { command } => commands::wrap(&path, command).await,
-        Commands::WrapEach {
-            command,
-            ignore_exit_code,
-            xarg,
-        } => commands::wrap_each(&path, command, ignore_exit_code, xarg).await,
-    }
+    };
+    command.instrument(logging::instance_span()).await
 }
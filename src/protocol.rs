@@ -0,0 +1,180 @@
+use std::{collections::BTreeSet, fmt::Display};
+
+use tokio::{io::AsyncReadExt as _, net::UnixStream};
+
+/// Read one `\n`-terminated line, the framing of both requests and responses.
+/// Reads a byte at a time so nothing after the line is taken off the stream.
+pub(crate) async fn read_line(stream: &mut UnixStream) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        line.push(byte);
+        if line.len() > 1024 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Line too long",
+            ));
+        }
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// A request sent by the client as the first line after connecting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Request {
+    /// Lease a database for as long as the connection stays open
+    Acquire(AcquireOptions),
+    /// Lease a database until it is explicitly released with its token
+    Detach,
+    /// Release a detached lease
+    Release(String),
+    /// Return the database leased by this connection without closing it
+    Return,
+    /// Force-release whichever lease holds the named database
+    Kill(String),
+    /// Report the pool's usage, resetting the peak and wait counters if set
+    Stats { reset: bool },
+    /// Shut the server down as if it had been interrupted
+    Shutdown,
+}
+
+/// Constraints on which database an acquire may be given, sent as
+/// `;`-separated items, e.g. `ACQUIRE:priority;capabilities=postgis,hstore;group=e2e`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct AcquireOptions {
+    /// May use databases held back by `PGM_RESERVE_FREE`
+    pub(crate) priority: bool,
+    /// Capability tags the database must have
+    pub(crate) capabilities: BTreeSet<String>,
+    /// Share one database with every connection presenting the same group token
+    pub(crate) group: Option<String>,
+    /// Answer `EMPTY` straight away instead of waiting for a free database
+    pub(crate) nowait: bool,
+    /// Wait for this database in particular, if it is in the pool
+    pub(crate) name: Option<String>,
+}
+
+impl AcquireOptions {
+    fn parse(payload: &str) -> Option<Self> {
+        let mut options = Self::default();
+        for item in payload.split(';').filter(|item| !item.is_empty()) {
+            match item.split_once('=') {
+                None if item == "priority" => options.priority = true,
+                None if item == "nowait" => options.nowait = true,
+                Some(("capabilities", capabilities)) => {
+                    options.capabilities = capabilities
+                        .split(',')
+                        .filter(|c| !c.is_empty())
+                        .map(String::from)
+                        .collect();
+                }
+                Some(("group", group)) if !group.is_empty() => {
+                    options.group = Some(group.to_string());
+                }
+                Some(("name", name)) if !name.is_empty() => {
+                    options.name = Some(name.to_string());
+                }
+                _ => return None,
+            }
+        }
+        Some(options)
+    }
+}
+
+impl Display for AcquireOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut items = Vec::new();
+        if self.priority {
+            items.push("priority".to_string());
+        }
+        if !self.capabilities.is_empty() {
+            let capabilities: Vec<_> = self.capabilities.iter().map(String::as_str).collect();
+            items.push(format!("capabilities={}", capabilities.join(",")));
+        }
+        if let Some(group) = &self.group {
+            items.push(format!("group={group}"));
+        }
+        if self.nowait {
+            items.push("nowait".to_string());
+        }
+        if let Some(name) = &self.name {
+            items.push(format!("name={name}"));
+        }
+        f.write_str(&items.join(";"))
+    }
+}
+
+impl Request {
+    pub(crate) fn parse(line: &str) -> Option<Self> {
+        let (prefix, payload) = line.trim_end_matches(['\r', '\n']).split_once(':')?;
+        match (prefix, payload) {
+            ("ACQUIRE", options) => AcquireOptions::parse(options).map(Self::Acquire),
+            ("DETACH", "") => Some(Self::Detach),
+            ("RELEASE", token) if !token.is_empty() => Some(Self::Release(token.to_string())),
+            ("RETURN", "") => Some(Self::Return),
+            ("KILL", name) if !name.is_empty() => Some(Self::Kill(name.to_string())),
+            ("STATS", "") => Some(Self::Stats { reset: false }),
+            ("STATS", "reset") => Some(Self::Stats { reset: true }),
+            ("SHUTDOWN", "") => Some(Self::Shutdown),
+            (_, _) => None,
+        }
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Acquire(options) => writeln!(f, "ACQUIRE:{options}"),
+            Self::Detach => writeln!(f, "DETACH:"),
+            Self::Release(token) => writeln!(f, "RELEASE:{token}"),
+            Self::Return => writeln!(f, "RETURN:"),
+            Self::Kill(name) => writeln!(f, "KILL:{name}"),
+            Self::Stats { reset: false } => writeln!(f, "STATS:"),
+            Self::Stats { reset: true } => writeln!(f, "STATS:reset"),
+            Self::Shutdown => writeln!(f, "SHUTDOWN:"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for request in [
+            Request::Acquire(AcquireOptions::default()),
+            Request::Acquire(AcquireOptions {
+                priority: true,
+                capabilities: ["hstore".to_string(), "postgis".to_string()].into(),
+                group: Some("e2e".to_string()),
+                nowait: true,
+                name: Some("test_db1".to_string()),
+            }),
+            Request::Detach,
+            Request::Release("abc123".to_string()),
+            Request::Return,
+            Request::Kill("test_db0".to_string()),
+            Request::Stats { reset: false },
+            Request::Stats { reset: true },
+            Request::Shutdown,
+        ] {
+            assert_eq!(Request::parse(&request.to_string()), Some(request));
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(Request::parse("HELLO:"), None);
+        assert_eq!(Request::parse("RELEASE:"), None);
+        assert_eq!(Request::parse("KILL:"), None);
+        assert_eq!(Request::parse("STATS:all"), None);
+        assert_eq!(Request::parse("ACQUIRE"), None);
+        assert_eq!(Request::parse("ACQUIRE:fast"), None);
+        assert_eq!(Request::parse("ACQUIRE:group="), None);
+        assert_eq!(Request::parse("ACQUIRE:name="), None);
+    }
+}
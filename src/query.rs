@@ -0,0 +1,328 @@
+use std::fmt::Write as _;
+
+use tokio::task::JoinHandle;
+use tokio_postgres::{Client, NoTls, SimpleQueryMessage};
+use tracing::{debug, info};
+
+use crate::util::json_string;
+
+/// How to print the rows returned by `pgmanager query`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct QueryResult {
+    columns: Vec<String>,
+    rows: Vec<Vec<Option<String>>>,
+}
+
+/// Connection settings for `db_name` taken from the libpq environment variables
+fn postgres_config(db_name: &str) -> tokio_postgres::Config {
+    let mut config = tokio_postgres::Config::new();
+    config
+        .host(std::env::var("PGHOST").as_deref().unwrap_or("localhost"))
+        .port(
+            std::env::var("PGPORT")
+                .ok()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(5432),
+        )
+        .user(
+            std::env::var("PGUSER")
+                .or_else(|_| std::env::var("USER"))
+                .as_deref()
+                .unwrap_or("postgres"),
+        )
+        .dbname(db_name);
+    if let Ok(password) = std::env::var("PGPASSWORD") {
+        config.password(password);
+    }
+    config
+}
+
+async fn connect(db_name: &str) -> Result<(Client, JoinHandle<()>), tokio_postgres::Error> {
+    let (client, connection) = postgres_config(db_name).connect(NoTls).await?;
+    let connection = tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Postgres connection error: {}", e);
+        }
+    });
+    Ok((client, connection))
+}
+
+/// The database to connect to when creating and dropping the pool's databases
+fn maintenance_database() -> String {
+    std::env::var("PGDATABASE").unwrap_or_else(|_| "postgres".to_string())
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Run `template` with `{name}` replaced for every database in `names` that doesn't exist yet,
+/// returning the names it created.
+pub(crate) async fn create_databases(
+    names: &[String],
+    template: &str,
+) -> Result<Vec<String>, tokio_postgres::Error> {
+    let (client, connection) = connect(&maintenance_database()).await?;
+    let mut created = Vec::new();
+    for name in names {
+        let existing = client
+            .query("SELECT 1 FROM pg_database WHERE datname = $1", &[name])
+            .await?;
+        if !existing.is_empty() {
+            debug!("Database {} already exists", name);
+            continue;
+        }
+        client
+            .batch_execute(&template.replace("{name}", &quote_identifier(name)))
+            .await?;
+        info!("Created database {}", name);
+        created.push(name.clone());
+    }
+    drop(client);
+    connection.await.ok();
+    Ok(created)
+}
+
+pub(crate) async fn drop_databases(names: &[String]) -> Result<(), tokio_postgres::Error> {
+    let (client, connection) = connect(&maintenance_database()).await?;
+    for name in names {
+        client
+            .batch_execute(&format!(
+                "DROP DATABASE IF EXISTS {}",
+                quote_identifier(name)
+            ))
+            .await?;
+        info!("Dropped database {}", name);
+    }
+    drop(client);
+    connection.await.ok();
+    Ok(())
+}
+
+/// Run `sql` against `db_name`, discarding any rows.
+pub(crate) async fn execute(db_name: &str, sql: &str) -> Result<(), tokio_postgres::Error> {
+    let (client, connection) = connect(db_name).await?;
+    client.batch_execute(sql).await?;
+    drop(client);
+    connection.await.ok();
+    Ok(())
+}
+
+pub(crate) async fn run(db_name: &str, sql: &str) -> Result<QueryResult, tokio_postgres::Error> {
+    let (client, connection) = connect(db_name).await?;
+
+    let mut result = QueryResult::default();
+    for message in client.simple_query(sql).await? {
+        match message {
+            SimpleQueryMessage::RowDescription(columns) => {
+                result.columns = columns.iter().map(|c| c.name().to_string()).collect();
+            }
+            SimpleQueryMessage::Row(row) => {
+                result.rows.push(
+                    (0..row.len())
+                        .map(|i| row.get(i).map(String::from))
+                        .collect(),
+                );
+            }
+            _ => {}
+        }
+    }
+    drop(client);
+    connection.await.ok();
+    Ok(result)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl QueryResult {
+    pub(crate) fn format(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Table => self.format_table(),
+            OutputFormat::Json => self.format_json(),
+            OutputFormat::Csv => self.format_csv(),
+        }
+    }
+
+    fn format_table(&self) -> String {
+        let mut widths: Vec<usize> = self.columns.iter().map(|c| c.len()).collect();
+        for row in &self.rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.as_deref().unwrap_or("").len());
+            }
+        }
+        let line = |values: Vec<&str>| {
+            values
+                .iter()
+                .zip(&widths)
+                .map(|(value, width)| format!(" {value:width$} "))
+                .collect::<Vec<_>>()
+                .join("|")
+                .trim_end()
+                .to_string()
+        };
+
+        let mut output = line(self.columns.iter().map(String::as_str).collect());
+        output.push('\n');
+        let separator: Vec<_> = widths.iter().map(|width| "-".repeat(width + 2)).collect();
+        output.push_str(&separator.join("+"));
+        output.push('\n');
+        for row in &self.rows {
+            output.push_str(&line(
+                row.iter().map(|v| v.as_deref().unwrap_or("")).collect(),
+            ));
+            output.push('\n');
+        }
+        let count = self.rows.len();
+        writeln!(
+            output,
+            "({} row{})",
+            count,
+            if count == 1 { "" } else { "s" }
+        )
+        .unwrap();
+        output
+    }
+
+    fn format_json(&self) -> String {
+        let rows: Vec<_> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let fields: Vec<_> = self
+                    .columns
+                    .iter()
+                    .zip(row)
+                    .map(|(column, value)| {
+                        let value = value.as_deref().map_or("null".to_string(), json_string);
+                        format!("{}:{}", json_string(column), value)
+                    })
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            })
+            .collect();
+        format!("[{}]\n", rows.join(","))
+    }
+
+    fn format_csv(&self) -> String {
+        let mut output = String::new();
+        let header: Vec<_> = self.columns.iter().map(|c| csv_field(c)).collect();
+        writeln!(output, "{}", header.join(",")).unwrap();
+        for row in &self.rows {
+            let fields: Vec<_> = row
+                .iter()
+                .map(|v| v.as_deref().map(csv_field).unwrap_or_default())
+                .collect();
+            writeln!(output, "{}", fields.join(",")).unwrap();
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select_one() -> QueryResult {
+        QueryResult {
+            columns: vec!["?column?".to_string()],
+            rows: vec![vec![Some("1".to_string())]],
+        }
+    }
+
+    #[test]
+    fn test_format_select_one() {
+        let result = select_one();
+        assert_eq!(
+            result.format(OutputFormat::Table),
+            " ?column?\n----------\n 1\n(1 row)\n"
+        );
+        assert_eq!(
+            result.format(OutputFormat::Json),
+            "[{\"?column?\":\"1\"}]\n"
+        );
+        assert_eq!(result.format(OutputFormat::Csv), "?column?\n1\n");
+    }
+
+    #[test]
+    fn test_format_escaping() {
+        let result = QueryResult {
+            columns: vec!["name".to_string(), "note".to_string()],
+            rows: vec![vec![Some("a \"b\", c".to_string()), None]],
+        };
+        assert_eq!(
+            result.format(OutputFormat::Json),
+            "[{\"name\":\"a \\\"b\\\", c\",\"note\":null}]\n"
+        );
+        assert_eq!(
+            result.format(OutputFormat::Csv),
+            "name,note\n\"a \"\"b\"\", c\",\n"
+        );
+    }
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(quote_identifier("myapp_test0"), "\"myapp_test0\"");
+        assert_eq!(quote_identifier("a\"b"), "\"a\"\"b\"");
+    }
+
+    /// Needs a reachable postgres where `PGUSER` may create databases
+    #[tokio::test]
+    #[ignore = "requires a running postgres"]
+    async fn test_create_and_drop_databases() {
+        async fn count(names: &[String]) -> i64 {
+            let (client, _connection) = connect(&maintenance_database()).await.unwrap();
+            let row = client
+                .query_one(
+                    "SELECT count(*) FROM pg_database WHERE datname = ANY($1)",
+                    &[&names],
+                )
+                .await
+                .unwrap();
+            row.get(0)
+        }
+
+        let prefix = format!("pgm_test_{:x}_", crate::util::random_u64());
+        let names: Vec<_> = (0..2).map(|n| format!("{prefix}{n}")).collect();
+        let created = create_databases(&names, "CREATE DATABASE {name}")
+            .await
+            .expect("Failed to create databases");
+        assert_eq!(created, names);
+        let created = create_databases(&names, "CREATE DATABASE {name}")
+            .await
+            .expect("Failed to create databases");
+        assert!(
+            created.is_empty(),
+            "Existing databases were reported as created"
+        );
+        assert_eq!(count(&names).await, 2);
+        for _ in 0..2 {
+            drop_databases(&names)
+                .await
+                .expect("Failed to drop databases");
+        }
+        assert_eq!(count(&names).await, 0);
+    }
+
+    /// Needs a reachable postgres with a database named by `PGDATABASE`
+    #[tokio::test]
+    #[ignore = "requires a running postgres"]
+    async fn test_run_select_one() {
+        let db_name = std::env::var("PGDATABASE").unwrap_or_else(|_| "postgres".to_string());
+        let result = run(&db_name, "SELECT 1").await.expect("Query failed");
+        assert_eq!(result, select_one());
+    }
+}
@@ -1,31 +1,207 @@
-use std::sync::atomic::AtomicUsize;
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use tracing::{debug, info};
 
 pub static USAGE: AtomicUsize = AtomicUsize::new(0);
 pub static PEAK_USAGE: AtomicUsize = AtomicUsize::new(0);
 pub static TOTAL_WAIT: AtomicUsize = AtomicUsize::new(0);
+pub static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
 
 pub(crate) fn increment_usage() {
-    let current = USAGE.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
-    let peak = PEAK_USAGE.load(std::sync::atomic::Ordering::Relaxed);
+    let current = USAGE.fetch_add(1, Ordering::Relaxed) + 1;
+    let peak = PEAK_USAGE.fetch_max(current, Ordering::Relaxed);
     if current > peak {
         debug!("Peak usage: {}", current);
-        PEAK_USAGE.store(current, std::sync::atomic::Ordering::Relaxed);
     }
 }
 
 pub(crate) fn decrement_usage() -> usize {
-    USAGE.fetch_sub(1, std::sync::atomic::Ordering::Relaxed)
+    USAGE.fetch_sub(1, Ordering::Relaxed)
+}
+
+/// Counters at one point in time, from [`snapshot`] or a single pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Databases currently leased
+    pub usage: usize,
+    /// Most databases leased at once since startup or the last [`reset`]
+    pub peak_usage: usize,
+    /// Time spent waiting for a free database since startup or the last [`reset`]
+    pub total_wait_ms: usize,
+    /// Client connections currently open
+    pub connections: usize,
+}
+
+impl StatsSnapshot {
+    /// Parse the `;`-separated `key=value` form written by [`Display`].
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let mut snapshot = Self::default();
+        for item in value.split(';') {
+            let (key, value) = item.split_once('=')?;
+            let value = value.parse().ok()?;
+            match key {
+                "usage" => snapshot.usage = value,
+                "peak_usage" => snapshot.peak_usage = value,
+                "total_wait_ms" => snapshot.total_wait_ms = value,
+                "connections" => snapshot.connections = value,
+                _ => return None,
+            }
+        }
+        Some(snapshot)
+    }
+}
+
+impl Display for StatsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "usage={};peak_usage={};total_wait_ms={};connections={}",
+            self.usage, self.peak_usage, self.total_wait_ms, self.connections
+        )
+    }
+}
+
+/// The process-wide counters, summed over every pool.
+pub fn snapshot() -> StatsSnapshot {
+    StatsSnapshot {
+        usage: USAGE.load(Ordering::Relaxed),
+        peak_usage: PEAK_USAGE.load(Ordering::Relaxed),
+        total_wait_ms: TOTAL_WAIT.load(Ordering::Relaxed),
+        connections: CONNECTIONS.load(Ordering::Relaxed),
+    }
+}
+
+/// Start measuring afresh, e.g. between test phases. Clears the wait time and
+/// brings the peak down to the current usage; current usage and connections
+/// are left alone since leases and connections are still open.
+pub fn reset() {
+    PEAK_USAGE.store(USAGE.load(Ordering::Relaxed), Ordering::Relaxed);
+    TOTAL_WAIT.store(0, Ordering::Relaxed);
 }
 
 pub(crate) fn log_usage() {
-    info!(
-        "Peak usage: {}",
-        PEAK_USAGE.load(std::sync::atomic::Ordering::Relaxed)
-    );
-    info!(
-        "Total wait time: {}ms",
-        TOTAL_WAIT.load(std::sync::atomic::Ordering::Relaxed)
-    );
+    info!("Peak usage: {}", PEAK_USAGE.load(Ordering::Relaxed));
+    info!("Total wait time: {}ms", TOTAL_WAIT.load(Ordering::Relaxed));
+}
+
+/// Usage of a single pool. Every update is also applied to the global aggregates.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    usage: AtomicUsize,
+    peak_usage: AtomicUsize,
+    total_wait: AtomicUsize,
+    connections: AtomicUsize,
+}
+
+impl Stats {
+    pub(crate) fn increment_usage(&self) {
+        let current = self.usage.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_usage.fetch_max(current, Ordering::Relaxed);
+        increment_usage();
+    }
+
+    pub(crate) fn decrement_usage(&self) {
+        self.usage.fetch_sub(1, Ordering::Relaxed);
+        decrement_usage();
+    }
+
+    pub(crate) fn add_wait(&self, ms: usize) {
+        self.total_wait.fetch_add(ms, Ordering::Relaxed);
+        TOTAL_WAIT.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    pub(crate) fn open_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn close_connection(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn usage(&self) -> usize {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    /// Client connections currently open, whether or not they hold a lease
+    pub(crate) fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn peak_usage(&self) -> usize {
+        self.peak_usage.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn total_wait(&self) -> usize {
+        self.total_wait.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            usage: self.usage(),
+            peak_usage: self.peak_usage(),
+            total_wait_ms: self.total_wait(),
+            connections: self.connections(),
+        }
+    }
+
+    /// Like [`reset`], for this pool only.
+    pub(crate) fn reset(&self) {
+        self.peak_usage.store(self.usage(), Ordering::Relaxed);
+        self.total_wait.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn log_usage(&self, pool: &str) {
+        debug!("Pool {} peak usage: {}", pool, self.peak_usage());
+        debug!("Pool {} total wait time: {}ms", pool, self.total_wait());
+        debug!("Pool {} databases still leased: {}", pool, self.usage());
+        debug!(
+            "Pool {} connections still open: {}",
+            pool,
+            self.connections()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_and_reset() {
+        let stats = Stats::default();
+        stats.open_connection();
+        stats.increment_usage();
+        stats.increment_usage();
+        stats.decrement_usage();
+        stats.add_wait(30);
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot,
+            StatsSnapshot {
+                usage: 1,
+                peak_usage: 2,
+                total_wait_ms: 30,
+                connections: 1,
+            }
+        );
+        assert_eq!(StatsSnapshot::parse(&snapshot.to_string()), Some(snapshot));
+
+        stats.reset();
+        assert_eq!(
+            stats.snapshot(),
+            StatsSnapshot {
+                usage: 1,
+                peak_usage: 1,
+                total_wait_ms: 0,
+                connections: 1,
+            }
+        );
+        stats.decrement_usage();
+        stats.close_connection();
+    }
 }
@@ -1,4 +1,9 @@
-use std::{env::VarError, str::FromStr};
+use std::{
+    env::VarError,
+    hash::{BuildHasher, RandomState},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use tracing::{error, warn};
 
@@ -8,12 +13,26 @@ fn get_prefixed_env_var(key: &str) -> Option<String> {
 }
 
 pub(crate) fn env_var_with_fallback(key: &str, fallback_key: &str) -> Option<String> {
-    let prefixed = std::env::var(key);
-    let fallback = std::env::var(fallback_key);
-    match (prefixed, fallback) {
+    let strict = optional_env_var("STRICT_ENV").unwrap_or(false);
+    lookup_with_fallback(key, fallback_key, strict, |k| std::env::var(k))
+}
+
+/// With `strict` (`PGM_STRICT_ENV=true`) a value only found under `fallback_key` panics.
+fn lookup_with_fallback(
+    key: &str,
+    fallback_key: &str,
+    strict: bool,
+    var: impl Fn(&str) -> Result<String, VarError>,
+) -> Option<String> {
+    match (var(key), var(fallback_key)) {
         (Ok(val), _) => Some(val),
+        (Err(VarError::NotPresent), Ok(_)) if strict => {
+            panic!(
+                "Environment variable {key} not found. Fallback {fallback_key} is not allowed with PGM_STRICT_ENV"
+            );
+        }
         (Err(VarError::NotPresent), Ok(val)) => {
-            warn!("Environment variable {key} not found. Using fallback {key}");
+            warn!("Environment variable {key} not found. Using fallback {fallback_key}");
             warn!("This behavior is deprecated and will panic in a future version.");
             Some(val)
         }
@@ -31,3 +50,69 @@ pub(crate) fn env_var_with_fallback(key: &str, fallback_key: &str) -> Option<Str
 pub(crate) fn env_var<T: FromStr>(key: &str) -> Option<T> {
     get_prefixed_env_var(key).and_then(|v| v.parse().ok())
 }
+
+/// Read an optional `PGM_` setting that has no unprefixed fallback.
+pub(crate) fn optional_env_var<T: FromStr>(key: &str) -> Option<T> {
+    let prefixed_key = format!("PGM_{}", key);
+    match std::env::var(&prefixed_key) {
+        Ok(val) => match val.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                error!("Environment variable {prefixed_key} has an invalid value: {val}");
+                None
+            }
+        },
+        Err(VarError::NotPresent) => None,
+        Err(VarError::NotUnicode(_)) => {
+            error!("Environment variable {prefixed_key} contains non-unicode data");
+            None
+        }
+    }
+}
+
+/// The effective uid of this process
+pub(crate) fn current_uid() -> u32 {
+    // SAFETY: geteuid has no preconditions and always succeeds
+    unsafe { libc::geteuid() }
+}
+
+/// A random value for identifiers that only need to be unlikely to collide.
+pub(crate) fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    RandomState::new().hash_one((std::process::id(), n))
+}
+
+/// Quote and escape `value` as a JSON string.
+pub(crate) fn json_string(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+
+    fn fallback_only(key: &str) -> Result<String, VarError> {
+        match key {
+            "SOCKET" => Ok("/tmp/pgm.sock".to_string()),
+            _ => Err(VarError::NotPresent),
+        }
+    }
+
+    #[test]
+    fn test_fallback_warns() {
+        let mut value = None;
+        let logs = test_helpers::capture_logs(|| {
+            value = lookup_with_fallback("PGM_SOCKET", "SOCKET", false, fallback_only);
+        });
+        assert_eq!(value.as_deref(), Some("/tmp/pgm.sock"));
+        assert!(logs.contains("Environment variable PGM_SOCKET not found. Using fallback SOCKET"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Fallback SOCKET is not allowed with PGM_STRICT_ENV")]
+    fn test_fallback_strict() {
+        lookup_with_fallback("PGM_SOCKET", "SOCKET", true, fallback_only);
+    }
+}